@@ -0,0 +1,303 @@
+use eframe::egui;
+use egui::{Color32, Pos2, Rect, Sense, Stroke, Vec2};
+use serde::{Serialize, Deserialize};
+
+use crate::compositing::Rgba;
+
+// Filter recipe held by an adjustment layer, it modifies everything composited below it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Adjustment {
+    // Both in -1.0..=1.0
+    BrightnessContrast { brightness: f32, contrast: f32 },
+    // Hue shift in degrees, saturation and lightness in -1.0..=1.0
+    HueSaturation { hue: f32, saturation: f32, lightness: f32 },
+    // Input black and white points in 0.0..=1.0 and a midtone gamma
+    Levels { black: f32, white: f32, gamma: f32 },
+    // Control points sorted by input value, both coordinates in 0.0..=1.0
+    Curves { points: Vec<[f32; 2]> },
+    Invert,
+    Threshold { level: f32 },
+}
+
+impl Adjustment {
+    // One adjustment of each kind with neutral settings
+    pub fn defaults() -> Vec<Adjustment> {
+        vec![
+            Adjustment::BrightnessContrast { brightness: 0.0, contrast: 0.0 },
+            Adjustment::HueSaturation { hue: 0.0, saturation: 0.0, lightness: 0.0 },
+            Adjustment::Levels { black: 0.0, white: 1.0, gamma: 1.0 },
+            Adjustment::Curves { points: vec![[0.0, 0.0], [1.0, 1.0]] },
+            Adjustment::Invert,
+            Adjustment::Threshold { level: 0.5 },
+        ]
+    }
+
+    // Localization key of the adjustment name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            Adjustment::BrightnessContrast { .. } => "adj_brightness_contrast",
+            Adjustment::HueSaturation { .. } => "adj_hue_saturation",
+            Adjustment::Levels { .. } => "adj_levels",
+            Adjustment::Curves { .. } => "adj_curves",
+            Adjustment::Invert => "adj_invert",
+            Adjustment::Threshold { .. } => "adj_threshold",
+        }
+    }
+
+    // Apply the filter to a straight (non premultiplied) color
+    pub fn apply(&self, c: [f32; 3]) -> [f32; 3] {
+        match self {
+            Adjustment::BrightnessContrast { brightness, contrast } => {
+                let factor = if *contrast >= 0.0 {
+                    1.0 / (1.0 - contrast.min(0.99))
+                } else {
+                    1.0 + contrast
+                };
+                c.map(|v| ((v + brightness - 0.5) * factor + 0.5).clamp(0.0, 1.0))
+            },
+            Adjustment::HueSaturation { hue, saturation, lightness } => {
+                let (h, s, l) = rgb_to_hsl(c);
+                let h = (h + hue / 360.0).rem_euclid(1.0);
+                let s = if *saturation >= 0.0 {
+                    s + (1.0 - s) * saturation
+                } else {
+                    s * (1.0 + saturation)
+                };
+                let l = if *lightness >= 0.0 {
+                    l + (1.0 - l) * lightness
+                } else {
+                    l * (1.0 + lightness)
+                };
+                hsl_to_rgb(h, s.clamp(0.0, 1.0), l.clamp(0.0, 1.0))
+            },
+            Adjustment::Levels { black, white, gamma } => {
+                let range = (white - black).max(1.0 / 255.0);
+                let exponent = 1.0 / gamma.max(0.01);
+                c.map(|v| ((v - black) / range).clamp(0.0, 1.0).powf(exponent))
+            },
+            Adjustment::Curves { points } => c.map(|v| evaluate_curve(points, v)),
+            Adjustment::Invert => c.map(|v| 1.0 - v),
+            Adjustment::Threshold { level } => {
+                let luminance = 0.299 * c[0] + 0.587 * c[1] + 0.114 * c[2];
+                if luminance >= *level { [1.0; 3] } else { [0.0; 3] }
+            },
+        }
+    }
+
+    // Apply the filter to a premultiplied color, keeping its alpha
+    pub fn apply_premultiplied(&self, color: Rgba) -> Rgba {
+        let alpha = color[3];
+        if alpha <= 0.0 {
+            return color;
+        }
+        let [r, g, b] = self.apply([color[0] / alpha, color[1] / alpha, color[2] / alpha]);
+        [r * alpha, g * alpha, b * alpha, alpha]
+    }
+}
+
+fn rgb_to_hsl(c: [f32; 3]) -> (f32, f32, f32) {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    let l = (max + min) / 2.0;
+    let d = max - min;
+    if d <= f32::EPSILON {
+        return (0.0, 0.0, l);
+    }
+    let s = if l > 0.5 { d / (2.0 - max - min) } else { d / (max + min) };
+    let h = if max == c[0] {
+        (c[1] - c[2]) / d + if c[1] < c[2] { 6.0 } else { 0.0 }
+    } else if max == c[1] {
+        (c[2] - c[0]) / d + 2.0
+    } else {
+        (c[0] - c[1]) / d + 4.0
+    };
+    (h / 6.0, s, l)
+}
+
+fn hsl_to_rgb(h: f32, s: f32, l: f32) -> [f32; 3] {
+    if s <= 0.0 {
+        return [l; 3];
+    }
+    let q = if l < 0.5 { l * (1.0 + s) } else { l + s - l * s };
+    let p = 2.0 * l - q;
+    let channel = |t: f32| {
+        let t = t.rem_euclid(1.0);
+        if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        }
+    };
+    [channel(h + 1.0 / 3.0), channel(h), channel(h - 1.0 / 3.0)]
+}
+
+// Monotone cubic interpolation through the control points
+pub fn evaluate_curve(points: &[[f32; 2]], x: f32) -> f32 {
+    match points.len() {
+        0 => return x,
+        1 => return points[0][1],
+        _ => {}
+    }
+    if x <= points[0][0] {
+        return points[0][1];
+    }
+    let last = points.len() - 1;
+    if x >= points[last][0] {
+        return points[last][1];
+    }
+
+    let segment = points.windows(2).position(|w| x < w[1][0]).unwrap_or(last - 1);
+    let slope = |i: usize| {
+        let dx = points[i + 1][0] - points[i][0];
+        if dx <= 0.0 { 0.0 } else { (points[i + 1][1] - points[i][1]) / dx }
+    };
+    let tangent = |i: usize| {
+        if i == 0 {
+            slope(0)
+        } else if i == last {
+            slope(last - 1)
+        } else {
+            let (a, b) = (slope(i - 1), slope(i));
+            // Harmonic mean of the neighbouring slopes keeps the curve monotone
+            if a * b <= 0.0 { 0.0 } else { 2.0 * a * b / (a + b) }
+        }
+    };
+
+    let [x0, y0] = points[segment];
+    let [x1, y1] = points[segment + 1];
+    let h = x1 - x0;
+    if h <= 0.0 {
+        return y0;
+    }
+    let t = (x - x0) / h;
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let value = (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+        + (t3 - 2.0 * t2 + t) * h * tangent(segment)
+        + (-2.0 * t3 + 3.0 * t2) * y1
+        + (t3 - t2) * h * tangent(segment + 1);
+    value.clamp(0.0, 1.0)
+}
+
+// What an editor did this frame, a drag from start to release makes one history step
+#[derive(Default)]
+pub struct EditorResponse {
+    pub changed: bool,
+    pub drag_started: bool,
+    pub drag_released: bool,
+}
+
+impl EditorResponse {
+    fn record(&mut self, response: &egui::Response) {
+        self.changed |= response.changed();
+        self.drag_started |= response.drag_started();
+        self.drag_released |= response.drag_released();
+    }
+}
+
+// Small interactive editor for curve points: drag to move, click to add, right-click to remove.
+pub fn curve_editor(ui: &mut egui::Ui, points: &mut Vec<[f32; 2]>) -> EditorResponse {
+    let size = Vec2::splat(ui.available_width().clamp(100.0, 160.0));
+    let (response, painter) = ui.allocate_painter(size, Sense::click_and_drag());
+    let rect = response.rect;
+    let to_screen = |p: [f32; 2]| Pos2::new(rect.left() + p[0] * rect.width(), rect.bottom() - p[1] * rect.height());
+    let to_curve = |pos: Pos2| [
+        ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+        ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+    ];
+
+    painter.rect_filled(rect, 0.0, Color32::from_gray(30));
+    painter.line_segment([rect.left_bottom(), rect.right_top()], Stroke::new(1.0, Color32::from_gray(70)));
+
+    let mut changed = false;
+    let grab_radius = 8.0;
+    let nearest = response.interact_pointer_pos().and_then(|pos| {
+        points.iter()
+            .enumerate()
+            .map(|(i, &p)| (i, to_screen(p).distance(pos)))
+            .filter(|&(_, d)| d <= grab_radius)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    });
+
+    if response.dragged_by(egui::PointerButton::Primary) {
+        if let (Some(i), Some(pos)) = (nearest, response.interact_pointer_pos()) {
+            let mut p = to_curve(pos);
+            // The end points keep their input value and points can't cross their neighbours
+            if i == 0 || i == points.len() - 1 {
+                p[0] = points[i][0];
+            } else {
+                p[0] = p[0].clamp(points[i - 1][0] + 0.01, points[i + 1][0] - 0.01);
+            }
+            points[i] = p;
+            changed = true;
+        }
+    } else if response.clicked() {
+        if let (None, Some(pos)) = (nearest, response.interact_pointer_pos()) {
+            let p = to_curve(pos);
+            let at = points.iter().position(|q| q[0] > p[0]).unwrap_or(points.len());
+            if at > 0 && at < points.len() {
+                points.insert(at, p);
+                changed = true;
+            }
+        }
+    } else if let Some(i) = nearest.filter(|&i| i > 0 && i < points.len() - 1 && response.clicked_by(egui::PointerButton::Secondary)) {
+        points.remove(i);
+        changed = true;
+    }
+
+    let samples: Vec<Pos2> = (0..=64)
+        .map(|i| {
+            let x = i as f32 / 64.0;
+            to_screen([x, evaluate_curve(points, x)])
+        })
+        .collect();
+    painter.add(egui::Shape::line(samples, Stroke::new(1.5, Color32::WHITE)));
+    for &p in points.iter() {
+        painter.circle_filled(to_screen(p), 3.5, Color32::from_rgb(200, 200, 255));
+    }
+    painter.rect_stroke(Rect::from_min_max(rect.min, rect.max), 0.0, Stroke::new(1.0, Color32::from_gray(90)));
+
+    EditorResponse {
+        changed,
+        drag_started: response.drag_started(),
+        drag_released: response.drag_released(),
+    }
+}
+
+// Sliders for the parameters of an adjustment
+pub fn adjustment_editor(ui: &mut egui::Ui, adjustment: &mut Adjustment, text: impl Fn(&str) -> String) -> EditorResponse {
+    let mut edit = EditorResponse::default();
+    match adjustment {
+        Adjustment::BrightnessContrast { brightness, contrast } => {
+            edit.record(&ui.add(egui::Slider::new(brightness, -1.0..=1.0).text(text("brightness"))));
+            edit.record(&ui.add(egui::Slider::new(contrast, -1.0..=1.0).text(text("contrast"))));
+        },
+        Adjustment::HueSaturation { hue, saturation, lightness } => {
+            edit.record(&ui.add(egui::Slider::new(hue, -180.0..=180.0).suffix("°").text(text("hue"))));
+            edit.record(&ui.add(egui::Slider::new(saturation, -1.0..=1.0).text(text("saturation"))));
+            edit.record(&ui.add(egui::Slider::new(lightness, -1.0..=1.0).text(text("lightness"))));
+        },
+        Adjustment::Levels { black, white, gamma } => {
+            edit.record(&ui.add(egui::Slider::new(black, 0.0..=1.0).text(text("black_point"))));
+            edit.record(&ui.add(egui::Slider::new(white, 0.0..=1.0).text(text("white_point"))));
+            edit.record(&ui.add(egui::Slider::new(gamma, 0.1..=10.0).logarithmic(true).text(text("gamma"))));
+            if *white <= *black {
+                *white = (*black + 0.01).min(1.0);
+                *black = *white - 0.01;
+            }
+        },
+        Adjustment::Curves { points } => {
+            edit = curve_editor(ui, points);
+        },
+        Adjustment::Invert => {},
+        Adjustment::Threshold { level } => {
+            edit.record(&ui.add(egui::Slider::new(level, 0.0..=1.0).text(text("threshold_level"))));
+        },
+    }
+    edit
+}
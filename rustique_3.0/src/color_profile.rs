@@ -0,0 +1,685 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use image::{ImageDecoder, ImageFormat};
+use rayon::prelude::*;
+
+// Pixels are edited in sRGB. Images are converted to it when they are opened, unless they
+// keep the values of their profile, and back to the profile of the document when they are
+// exported, with the profile embedded.
+// Only matrix/TRC RGB profiles can be converted, which covers the usual working spaces
+// (Adobe RGB, Display P3, ProPhoto...). Pixels tagged with other profiles are left as they
+// are and get the same profile back on export.
+
+// sRGB primaries adapted to the D50 white of the profile connection space
+const SRGB_COLORANTS: [[f32; 3]; 3] = [
+    [0.4360747, 0.2225045, 0.0139322],
+    [0.3850649, 0.7168786, 0.0971045],
+    [0.1430804, 0.0606169, 0.7141733],
+];
+const D50: [f32; 3] = [0.9642, 1.0, 0.8249];
+// Differences below this are rounding noise of the profile encoding
+const SRGB_TOLERANCE: f32 = 2e-3;
+// APP2 segments carry at most 65533 bytes, minus the marker and the chunk numbers
+const JPEG_ICC_CHUNK: usize = 65519;
+// TIFF tag holding the profile
+const TIFF_ICC_TAG: u16 = 34675;
+
+// Tone response curve of one channel, maps encoded values to linear light
+#[derive(Clone, PartialEq)]
+enum Curve {
+    Table(Vec<f32>),
+    // ICC parametric function, every type is stored as the general form
+    // y = (a * x + b)^g + e for x >= d, y = c * x + f below
+    Parametric { g: f32, a: f32, b: f32, c: f32, d: f32, e: f32, f: f32 },
+}
+
+impl Curve {
+    fn gamma(g: f32) -> Self {
+        Curve::Parametric { g, a: 1.0, b: 0.0, c: 0.0, d: 0.0, e: 0.0, f: 0.0 }
+    }
+
+    fn srgb() -> Self {
+        Curve::Parametric { g: 2.4, a: 1.0 / 1.055, b: 0.055 / 1.055, c: 1.0 / 12.92, d: 0.04045, e: 0.0, f: 0.0 }
+    }
+
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            Curve::Table(table) => {
+                let position = x.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+                let i = (position as usize).min(table.len() - 2);
+                let t = position - i as f32;
+                table[i] + (table[i + 1] - table[i]) * t
+            },
+            &Curve::Parametric { g, a, b, c, d, e, f } => {
+                if x >= d {
+                    (a * x + b).max(0.0).powf(g) + e
+                } else {
+                    c * x + f
+                }
+            },
+        }
+    }
+
+    fn invert(&self, y: f32) -> f32 {
+        match self {
+            Curve::Table(table) => {
+                // Tables are increasing, find the segment holding y
+                let y = y.clamp(table[0], table[table.len() - 1]);
+                let i = table.partition_point(|&v| v < y).clamp(1, table.len() - 1);
+                let (low, high) = (table[i - 1], table[i]);
+                let t = if high > low { (y - low) / (high - low) } else { 0.0 };
+                (i as f32 - 1.0 + t) / (table.len() - 1) as f32
+            },
+            &Curve::Parametric { g, a, b, c, d, e, f } => {
+                if y >= self.eval(d) && a != 0.0 {
+                    (((y - e).max(0.0).powf(1.0 / g) - b) / a).max(d)
+                } else if c != 0.0 {
+                    (y - f) / c
+                } else {
+                    d
+                }
+            },
+        }
+    }
+}
+
+// Colorimetry of a matrix/TRC RGB profile
+#[derive(Clone, PartialEq)]
+struct RgbSpace {
+    // Columns are the XYZ (D50) of the red, green and blue primaries
+    colorants: [[f32; 3]; 3],
+    curves: [Curve; 3],
+}
+
+impl RgbSpace {
+    fn srgb() -> Self {
+        Self {
+            colorants: SRGB_COLORANTS,
+            curves: [Curve::srgb(), Curve::srgb(), Curve::srgb()],
+        }
+    }
+
+    // Same colorimetry as sRGB, up to the precision of the profile encoding
+    fn is_srgb(&self) -> bool {
+        let srgb = Curve::srgb();
+        let same_colorants = self.colorants.iter().flatten()
+            .zip(SRGB_COLORANTS.iter().flatten())
+            .all(|(a, b)| (a - b).abs() < SRGB_TOLERANCE);
+        same_colorants && self.curves.iter().all(|curve| {
+            (0..=32).all(|i| {
+                let x = i as f32 / 32.0;
+                (curve.eval(x) - srgb.eval(x)).abs() < SRGB_TOLERANCE
+            })
+        })
+    }
+
+    // Matrix taking linear RGB of this space to linear sRGB
+    fn to_srgb_matrix(&self) -> Option<[[f32; 3]; 3]> {
+        let to_xyz = transpose(self.colorants);
+        let from_xyz = invert(transpose(SRGB_COLORANTS))?;
+        Some(multiply(from_xyz, to_xyz))
+    }
+}
+
+// ICC profile attached to a document
+#[derive(Clone, PartialEq)]
+pub struct ColorProfile {
+    data: Vec<u8>,
+    name: String,
+    // None when the profile is not a matrix/TRC RGB one
+    space: Option<RgbSpace>,
+}
+
+impl ColorProfile {
+    // Check and read an ICC profile
+    pub fn from_icc(data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < 132 || &data[36..40] != b"acsp" {
+            return Err("not an ICC profile".to_string());
+        }
+        if &data[16..20] != b"RGB " {
+            return Err("not an RGB profile".to_string());
+        }
+        let name = read_tag(&data, b"desc")
+            .and_then(read_text)
+            .unwrap_or_else(|| "ICC".to_string());
+        let space = read_rgb_space(&data);
+        Ok(Self { data, name, space })
+    }
+
+    // Profile used when a document has none, embedded in exports so they don't rely on
+    // the viewer assuming sRGB
+    pub fn srgb() -> Self {
+        Self {
+            data: build_srgb_profile(),
+            name: "sRGB".to_string(),
+            space: Some(RgbSpace::srgb()),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Whether pixels can be converted from and to the profile
+    pub fn can_convert(&self) -> bool {
+        self.space.is_some()
+    }
+
+    // Convert straight pixels of this profile to sRGB, alpha is left as is
+    pub fn convert_to_srgb(&self, pixels: &mut [[f32; 4]]) {
+        let Some((space, matrix)) = self.conversion() else {
+            return;
+        };
+        let srgb = Curve::srgb();
+        pixels.par_iter_mut().for_each(|pixel| {
+            let linear = [0, 1, 2].map(|c| space.curves[c].eval(pixel[c]));
+            let converted = apply(matrix, linear);
+            for c in 0..3 {
+                pixel[c] = srgb.invert(converted[c]);
+            }
+        });
+    }
+
+    // Convert straight sRGB pixels to this profile
+    pub fn convert_from_srgb(&self, pixels: &mut [[f32; 4]]) {
+        let Some((space, matrix)) = self.conversion() else {
+            return;
+        };
+        let Some(matrix) = invert(matrix) else {
+            return;
+        };
+        let srgb = Curve::srgb();
+        pixels.par_iter_mut().for_each(|pixel| {
+            let linear = [0, 1, 2].map(|c| srgb.eval(pixel[c]));
+            let converted = apply(matrix, linear);
+            for c in 0..3 {
+                pixel[c] = space.curves[c].invert(converted[c]);
+            }
+        });
+    }
+
+    // Space and matrix to sRGB, None when there is nothing to convert
+    fn conversion(&self) -> Option<(&RgbSpace, [[f32; 3]; 3])> {
+        let space = self.space.as_ref().filter(|space| !space.is_srgb())?;
+        Some((space, space.to_srgb_matrix()?))
+    }
+}
+
+// Profile embedded in an image file, only the formats able to carry one are read
+pub fn read_embedded(path: &Path, format: ImageFormat) -> Option<Vec<u8>> {
+    let reader = BufReader::new(File::open(path).ok()?);
+    match format {
+        ImageFormat::Png => image::codecs::png::PngDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Jpeg => image::codecs::jpeg::JpegDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Tiff => image::codecs::tiff::TiffDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::WebP => image::codecs::webp::WebPDecoder::new(reader).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+// Write straight RGBA samples as a PNG, 8 or 16 bits per channel
+pub fn write_png(path: &Path, width: u32, height: u32, samples: Samples, profile: &ColorProfile) -> Result<(), String> {
+    let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let mut info = png::Info::with_size(width, height);
+    info.color_type = png::ColorType::Rgba;
+    info.icc_profile = Some(profile.data().into());
+    let data = match samples {
+        Samples::Eight(data) => {
+            info.bit_depth = png::BitDepth::Eight;
+            data
+        },
+        // PNG stores 16-bit samples big endian
+        Samples::Sixteen(data) => {
+            info.bit_depth = png::BitDepth::Sixteen;
+            data.iter().flat_map(|v| v.to_be_bytes()).collect()
+        },
+    };
+    let encoder = png::Encoder::with_info(file, info).map_err(|e| e.to_string())?;
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&data).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())
+}
+
+// The TIFF profile tag is an UNDEFINED byte array, plain byte slices are written as BYTE
+struct IccTag<'a>(&'a [u8]);
+
+impl tiff::encoder::TiffValue for IccTag<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: tiff::tags::Type = tiff::tags::Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Borrowed(self.0)
+    }
+}
+
+// Write straight RGBA samples as a TIFF, 8 or 16 bits per channel
+pub fn write_tiff(path: &Path, width: u32, height: u32, samples: Samples, profile: &ColorProfile) -> Result<(), String> {
+    use tiff::encoder::{colortype, TiffEncoder};
+    use tiff::tags::Tag;
+
+    let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    let mut encoder = TiffEncoder::new(file).map_err(|e| e.to_string())?;
+    match samples {
+        Samples::Eight(data) => {
+            let mut image = encoder.new_image::<colortype::RGBA8>(width, height).map_err(|e| e.to_string())?;
+            image.encoder().write_tag(Tag::Unknown(TIFF_ICC_TAG), IccTag(profile.data())).map_err(|e| e.to_string())?;
+            image.write_data(&data).map_err(|e| e.to_string())
+        },
+        Samples::Sixteen(data) => {
+            let mut image = encoder.new_image::<colortype::RGBA16>(width, height).map_err(|e| e.to_string())?;
+            image.encoder().write_tag(Tag::Unknown(TIFF_ICC_TAG), IccTag(profile.data())).map_err(|e| e.to_string())?;
+            image.write_data(&data).map_err(|e| e.to_string())
+        },
+    }
+}
+
+// Write straight RGBA samples as a JPEG, alpha is dropped by the encoder
+pub fn write_jpeg(path: &Path, width: u32, height: u32, data: &[u8], profile: &ColorProfile) -> Result<(), String> {
+    let mut encoded = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new(&mut encoded)
+        .encode(data, width, height, image::ColorType::Rgba8)
+        .map_err(|e| e.to_string())?;
+
+    // The profile goes in APP2 segments numbered on one byte
+    let chunks: Vec<&[u8]> = profile.data().chunks(JPEG_ICC_CHUNK).collect();
+    if chunks.len() > 255 {
+        return Err("color profile too large for JPEG".to_string());
+    }
+    let mut segments = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        segments.extend_from_slice(&[0xFF, 0xE2]);
+        segments.extend_from_slice(&((chunk.len() + 16) as u16).to_be_bytes());
+        segments.extend_from_slice(b"ICC_PROFILE\0");
+        segments.extend_from_slice(&[i as u8 + 1, chunks.len() as u8]);
+        segments.extend_from_slice(chunk);
+    }
+    // After the start of image marker, or after the JFIF APP0 segment that must follow it
+    let insert_at = match encoded.get(2..6) {
+        Some(&[0xFF, 0xE0, high, low]) => 4 + u16::from_be_bytes([high, low]) as usize,
+        _ => 2,
+    };
+    encoded.splice(insert_at..insert_at, segments);
+
+    let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    write_all(file, &encoded)
+}
+
+// Interleaved RGBA samples of an exported image
+pub enum Samples {
+    Eight(Vec<u8>),
+    Sixteen(Vec<u16>),
+}
+
+fn write_all(mut file: BufWriter<File>, data: &[u8]) -> Result<(), String> {
+    file.write_all(data).and_then(|_| file.flush()).map_err(|e| e.to_string())
+}
+
+// Reading profiles
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_s15_fixed16(data: &[u8], offset: usize) -> Option<f32> {
+    read_u32(data, offset).map(|v| v as i32 as f32 / 65536.0)
+}
+
+// Data of a tag, found through the tag table following the header
+fn read_tag<'a>(data: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let count = read_u32(data, 128)? as usize;
+    (0..count.min(1024)).find_map(|i| {
+        let entry = 132 + i * 12;
+        if data.get(entry..entry + 4)? != signature {
+            return None;
+        }
+        let offset = read_u32(data, entry + 4)? as usize;
+        let size = read_u32(data, entry + 8)? as usize;
+        data.get(offset..offset.checked_add(size)?)
+    })
+}
+
+fn read_xyz(tag: &[u8]) -> Option<[f32; 3]> {
+    if tag.get(0..4)? != b"XYZ " {
+        return None;
+    }
+    Some([read_s15_fixed16(tag, 8)?, read_s15_fixed16(tag, 12)?, read_s15_fixed16(tag, 16)?])
+}
+
+fn read_curve(tag: &[u8]) -> Option<Curve> {
+    match tag.get(0..4)? {
+        b"curv" => {
+            let count = read_u32(tag, 8)? as usize;
+            match count {
+                0 => Some(Curve::gamma(1.0)),
+                1 => Some(Curve::gamma(read_u16(tag, 12)? as f32 / 256.0)),
+                _ => {
+                    let table = (0..count)
+                        .map(|i| read_u16(tag, 12 + i * 2).map(|v| v as f32 / 65535.0))
+                        .collect::<Option<Vec<f32>>>()?;
+                    Some(Curve::Table(table))
+                },
+            }
+        },
+        b"para" => {
+            let kind = read_u16(tag, 8)?;
+            let count = [1, 3, 4, 5, 7].get(kind as usize).copied()?;
+            let p = (0..count).map(|i| read_s15_fixed16(tag, 12 + i * 4)).collect::<Option<Vec<f32>>>()?;
+            let g = p[0];
+            // Types 1 and 2 start the curve at -b/a
+            if (kind == 1 || kind == 2) && p[1] == 0.0 {
+                return None;
+            }
+            Some(match kind {
+                0 => Curve::gamma(g),
+                1 => Curve::Parametric { g, a: p[1], b: p[2], c: 0.0, d: -p[2] / p[1], e: 0.0, f: 0.0 },
+                2 => Curve::Parametric { g, a: p[1], b: p[2], c: 0.0, d: -p[2] / p[1], e: p[3], f: p[3] },
+                3 => Curve::Parametric { g, a: p[1], b: p[2], c: p[3], d: p[4], e: 0.0, f: 0.0 },
+                _ => Curve::Parametric { g, a: p[1], b: p[2], c: p[3], d: p[4], e: p[5], f: p[6] },
+            })
+        },
+        _ => None,
+    }
+}
+
+fn read_rgb_space(data: &[u8]) -> Option<RgbSpace> {
+    if data.get(20..24)? != b"XYZ " {
+        return None;
+    }
+    let colorants = [
+        read_xyz(read_tag(data, b"rXYZ")?)?,
+        read_xyz(read_tag(data, b"gXYZ")?)?,
+        read_xyz(read_tag(data, b"bXYZ")?)?,
+    ];
+    let curves = [
+        read_curve(read_tag(data, b"rTRC")?)?,
+        read_curve(read_tag(data, b"gTRC")?)?,
+        read_curve(read_tag(data, b"bTRC")?)?,
+    ];
+    Some(RgbSpace { colorants, curves })
+}
+
+// Description of the profile, in the ASCII form of version 2 or the first
+// localized string of version 4
+fn read_text(tag: &[u8]) -> Option<String> {
+    let text = match tag.get(0..4)? {
+        b"desc" => {
+            let count = read_u32(tag, 8)? as usize;
+            String::from_utf8_lossy(tag.get(12..12 + count)?).to_string()
+        },
+        b"mluc" => {
+            let length = read_u32(tag, 20)? as usize;
+            let offset = read_u32(tag, 24)? as usize;
+            let units: Vec<u16> = tag.get(offset..offset + length)?
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        },
+        _ => return None,
+    };
+    let text = text.trim_end_matches('\0').trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+// Writing the built-in sRGB profile, a version 2 display profile
+fn build_srgb_profile() -> Vec<u8> {
+    fn s15_fixed16(v: f32) -> [u8; 4] {
+        ((v * 65536.0).round() as i32).to_be_bytes()
+    }
+    fn xyz(v: [f32; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for c in v {
+            tag.extend_from_slice(&s15_fixed16(c));
+        }
+        tag
+    }
+
+    let name = b"sRGB IEC61966-2.1\0";
+    let mut desc = b"desc\0\0\0\0".to_vec();
+    desc.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    desc.extend_from_slice(name);
+    // Empty Unicode and ScriptCode descriptions
+    desc.extend_from_slice(&[0; 78]);
+
+    let mut text = b"text\0\0\0\0".to_vec();
+    text.extend_from_slice(b"No copyright, use freely\0");
+
+    let srgb = Curve::srgb();
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    curve.extend_from_slice(&1024u32.to_be_bytes());
+    for i in 0..1024 {
+        let value = srgb.eval(i as f32 / 1023.0);
+        curve.extend_from_slice(&((value * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    let tags: [(&[u8; 4], &Vec<u8>); 9] = [
+        (b"desc", &desc),
+        (b"cprt", &text),
+        (b"wtpt", &xyz(D50)),
+        (b"rXYZ", &xyz(SRGB_COLORANTS[0])),
+        (b"gXYZ", &xyz(SRGB_COLORANTS[1])),
+        (b"bXYZ", &xyz(SRGB_COLORANTS[2])),
+        (b"rTRC", &curve),
+        (b"gTRC", &curve),
+        (b"bTRC", &curve),
+    ];
+
+    // Tag data follows the tag table, aligned on 4 bytes, the three curves share theirs
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut body: Vec<u8> = Vec::new();
+    let mut curve_offset = None;
+    let data_start = 128 + 4 + tags.len() * 12;
+    for (signature, data) in tags {
+        let offset = match curve_offset.filter(|_| signature.ends_with(b"TRC")) {
+            Some(offset) => offset,
+            None => {
+                let offset = data_start + body.len();
+                body.extend_from_slice(data);
+                body.resize(body.len().next_multiple_of(4), 0);
+                offset
+            },
+        };
+        if signature.ends_with(b"TRC") {
+            curve_offset = Some(offset);
+        }
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&(offset as u32).to_be_bytes());
+        table.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    }
+
+    let size = data_start + body.len();
+    let mut header = vec![0; 128];
+    header[0..4].copy_from_slice(&(size as u32).to_be_bytes());
+    header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+    header[12..16].copy_from_slice(b"mntr");
+    header[16..20].copy_from_slice(b"RGB ");
+    header[20..24].copy_from_slice(b"XYZ ");
+    header[36..40].copy_from_slice(b"acsp");
+    for (c, v) in D50.iter().enumerate() {
+        header[68 + c * 4..72 + c * 4].copy_from_slice(&s15_fixed16(*v));
+    }
+
+    let mut profile = header;
+    profile.extend(table);
+    profile.extend(body);
+    profile
+}
+
+// 3x3 matrix helpers
+fn transpose(m: [[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    [0, 1, 2].map(|r| [0, 1, 2].map(|c| m[c][r]))
+}
+
+fn multiply(a: [[f32; 3]; 3], b: [[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    [0, 1, 2].map(|r| [0, 1, 2].map(|c| (0..3).map(|k| a[r][k] * b[k][c]).sum()))
+}
+
+fn apply(m: [[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|r| m[r][0] * v[0] + m[r][1] * v[1] + m[r][2] * v[2])
+}
+
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let determinant: f32 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+    // The inverse is the transposed cofactor matrix over the determinant
+    Some([0, 1, 2].map(|r| [0, 1, 2].map(|c| cofactor(c, r) / determinant)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The built-in profile with every curve replaced by a pure gamma
+    fn gamma_profile(gamma: f32) -> ColorProfile {
+        let mut data = build_srgb_profile();
+        // The three curves share one tag
+        let curve = data.windows(4).position(|window| window == b"curv").unwrap();
+        data[curve + 8..curve + 12].copy_from_slice(&1u32.to_be_bytes());
+        data[curve + 12..curve + 14].copy_from_slice(&((gamma * 256.0).round() as u16).to_be_bytes());
+        ColorProfile::from_icc(data).unwrap()
+    }
+
+    fn assert_close(actual: &[[f32; 4]], expected: &[[f32; 4]]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(a.iter().zip(e).all(|(a, e)| (a - e).abs() < 1e-3), "{a:?} != {e:?}");
+        }
+    }
+
+    #[test]
+    fn built_in_srgb_reads_back() {
+        let profile = ColorProfile::from_icc(ColorProfile::srgb().data().to_vec()).unwrap();
+        assert_eq!(profile.name(), "sRGB IEC61966-2.1");
+        assert!(profile.can_convert());
+        assert!(profile.space.as_ref().is_some_and(RgbSpace::is_srgb));
+        // Nothing to convert for sRGB itself
+        let colors = [[0.2, 0.5, 0.9, 0.5]];
+        let mut converted = colors;
+        profile.convert_to_srgb(&mut converted);
+        assert_eq!(converted, colors);
+    }
+
+    #[test]
+    fn colors_round_trip_through_a_profile() {
+        let profile = gamma_profile(1.8);
+        assert!(profile.can_convert());
+        // Values over 1 are kept on both sides
+        let colors = [[0.2, 0.5, 0.9, 0.5], [0.0, 1.0, 0.0, 1.0], [0.1, 1.3, 0.5, 1.0]];
+        let mut converted = colors;
+        profile.convert_from_srgb(&mut converted);
+        assert!((converted[0][1] - 0.5).abs() > 0.01);
+        assert!(converted[2][1] > 1.0);
+        assert_eq!(converted[0][3], 0.5);
+        profile.convert_to_srgb(&mut converted);
+        assert_close(&converted, &colors);
+    }
+
+    #[test]
+    fn broken_profiles_are_rejected() {
+        let data = build_srgb_profile();
+        assert!(ColorProfile::from_icc(data[..100].to_vec()).is_err());
+        let mut not_rgb = data.clone();
+        not_rgb[16..20].copy_from_slice(b"GRAY");
+        assert!(ColorProfile::from_icc(not_rgb).is_err());
+
+        // A tag pointing past the end leaves the profile without colorimetry
+        let mut past_end = data.clone();
+        let entry = (0..9).map(|i| 132 + i * 12).find(|&entry| &past_end[entry..entry + 4] == b"rXYZ").unwrap();
+        past_end[entry + 4..entry + 8].copy_from_slice(&(data.len() as u32 - 8).to_be_bytes());
+        assert_eq!(read_tag(&past_end, b"rXYZ"), None);
+        let profile = ColorProfile::from_icc(past_end).unwrap();
+        assert!(!profile.can_convert());
+    }
+
+    fn para(kind: u16, params: &[f32]) -> Vec<u8> {
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend_from_slice(&kind.to_be_bytes());
+        tag.extend_from_slice(&[0, 0]);
+        for &p in params {
+            tag.extend_from_slice(&((p * 65536.0).round() as i32).to_be_bytes());
+        }
+        tag
+    }
+
+    #[test]
+    fn parametric_curves() {
+        let curve = read_curve(&para(3, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])).unwrap();
+        for x in [-0.5, 0.0, 0.02, 0.5, 1.0, 1.5] {
+            assert!((curve.invert(curve.eval(x)) - x).abs() < 1e-3, "{x}");
+        }
+        assert!(read_curve(&para(1, &[2.2, 1.0, 0.0])).is_some());
+        // A zero slope has no start for the curve
+        assert!(read_curve(&para(1, &[2.2, 0.0, 0.1])).is_none());
+        assert!(read_curve(&para(2, &[2.2, 0.0, 0.1, 0.0])).is_none());
+        // Missing parameters
+        assert!(read_curve(&para(4, &[2.2, 1.0])).is_none());
+        assert!(read_curve(&para(5, &[2.2])).is_none());
+    }
+
+    // Markers and lengths of the segments before the image data
+    fn jpeg_segments(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut segments = Vec::new();
+        let mut at = 2;
+        while bytes[at] == 0xFF && bytes[at + 1] != 0xDA {
+            let length = u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) as usize;
+            segments.push((bytes[at + 1], bytes[at + 4..at + 2 + length].to_vec()));
+            at += 2 + length;
+        }
+        segments
+    }
+
+    #[test]
+    fn jpeg_profiles_follow_app0_in_chunks() {
+        // Big enough for three segments
+        let mut data = build_srgb_profile();
+        data.resize(JPEG_ICC_CHUNK * 2 + 1000, 7);
+        let profile = ColorProfile::from_icc(data.clone()).unwrap();
+        let path = std::env::temp_dir().join(format!("rustique-icc-{}.jpg", std::process::id()));
+        let written = write_jpeg(&path, 4, 4, &[128; 64], &profile).map(|_| std::fs::read(&path));
+        let embedded = read_embedded(&path, ImageFormat::Jpeg);
+        std::fs::remove_file(&path).ok();
+        let bytes = written.unwrap().unwrap();
+
+        let segments = jpeg_segments(&bytes);
+        assert_eq!(segments[0].0, 0xE0);
+        let chunks: Vec<&[u8]> = segments[1..4].iter()
+            .map(|(marker, segment)| {
+                assert_eq!(*marker, 0xE2);
+                assert_eq!(&segment[..12], b"ICC_PROFILE\0");
+                &segment[12..]
+            })
+            .collect();
+        assert_eq!(chunks.iter().map(|chunk| [chunk[0], chunk[1]]).collect::<Vec<_>>(), vec![[1, 3], [2, 3], [3, 3]]);
+        assert_eq!(chunks.iter().flat_map(|chunk| &chunk[2..]).copied().collect::<Vec<u8>>(), data);
+        assert_eq!(embedded, Some(data));
+    }
+
+    #[test]
+    fn jpeg_refuses_profiles_over_255_chunks() {
+        let mut data = build_srgb_profile();
+        data.resize(JPEG_ICC_CHUNK * 255 + 1, 0);
+        let profile = ColorProfile::from_icc(data).unwrap();
+        let path = std::env::temp_dir().join(format!("rustique-icc-large-{}.jpg", std::process::id()));
+        assert!(write_jpeg(&path, 4, 4, &[128; 64], &profile).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,429 @@
+use egui::Color32;
+use serde::{Serialize, Deserialize};
+
+// Accumulator for compositing, premultiplied RGBA in the 0.0..=1.0 range
+pub type Rgba = [f32; 4];
+
+pub const TRANSPARENT: Rgba = [0.0, 0.0, 0.0, 0.0];
+
+// egui premultiplies Color32 in linear space while compositing premultiplies the sRGB
+// values, so colors go through their straight form between the two
+#[inline]
+pub fn from_color32(color: Color32) -> Rgba {
+    premultiply(color.to_srgba_unmultiplied().map(|v| v as f32 / 255.0))
+}
+
+#[inline]
+pub fn to_color32(color: Rgba) -> Color32 {
+    let [r, g, b, a] = unpremultiply(color).map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8);
+    Color32::from_rgba_unmultiplied(r, g, b, a)
+}
+
+// Straight (unassociated) RGBA to premultiplied
+#[inline]
+pub fn premultiply(color: [f32; 4]) -> Rgba {
+    [color[0] * color[3], color[1] * color[3], color[2] * color[3], color[3]]
+}
+
+// Premultiplied to straight RGBA, fully transparent colors come out black
+#[inline]
+pub fn unpremultiply(color: Rgba) -> [f32; 4] {
+    if color[3] <= 0.0 {
+        return TRANSPARENT;
+    }
+    [color[0] / color[3], color[1] / color[3], color[2] / color[3], color[3]]
+}
+
+// sRGB transfer function, encoded value to linear light
+#[inline]
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[inline]
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Premultiplied sRGB color to premultiplied linear light, alpha is left as it is
+#[inline]
+pub fn to_linear(color: Rgba) -> Rgba {
+    let [r, g, b, a] = unpremultiply(color);
+    premultiply([srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a])
+}
+
+#[inline]
+pub fn from_linear(color: Rgba) -> Rgba {
+    let [r, g, b, a] = unpremultiply(color);
+    premultiply([linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b), a])
+}
+
+// Multiply every channel by a factor (used for layer opacity)
+#[inline]
+pub fn scale(color: Rgba, factor: f32) -> Rgba {
+    [color[0] * factor, color[1] * factor, color[2] * factor, color[3] * factor]
+}
+
+// Linear interpolation between two colors
+#[inline]
+pub fn lerp(from: Rgba, to: Rgba, t: f32) -> Rgba {
+    [
+        from[0] + (to[0] - from[0]) * t,
+        from[1] + (to[1] - from[1]) * t,
+        from[2] + (to[2] - from[2]) * t,
+        from[3] + (to[3] - from[3]) * t,
+    ]
+}
+
+// Porter-Duff source-over of premultiplied colors
+#[inline]
+pub fn source_over(dst: Rgba, src: Rgba) -> Rgba {
+    let inv = 1.0 - src[3];
+    [
+        src[0] + dst[0] * inv,
+        src[1] + dst[1] * inv,
+        src[2] + dst[2] * inv,
+        src[3] + dst[3] * inv,
+    ]
+}
+
+// Composite a color over another one, opaque colors simply replace it
+#[inline]
+pub fn blend_colors(dst: Option<Rgba>, src: Rgba) -> Rgba {
+    match dst {
+        Some(dst) if src[3] < 1.0 => source_over(dst, src),
+        _ => src,
+    }
+}
+
+// Blend modes available for layers, the mixing formulas follow the W3C compositing spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Add,
+    Subtract,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 18] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::HardLight,
+        BlendMode::SoftLight,
+        BlendMode::Difference,
+        BlendMode::Exclusion,
+        BlendMode::Add,
+        BlendMode::Subtract,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity,
+    ];
+    
+    // Localization key of the mode name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "blend_normal",
+            BlendMode::Multiply => "blend_multiply",
+            BlendMode::Screen => "blend_screen",
+            BlendMode::Overlay => "blend_overlay",
+            BlendMode::Darken => "blend_darken",
+            BlendMode::Lighten => "blend_lighten",
+            BlendMode::ColorDodge => "blend_color_dodge",
+            BlendMode::ColorBurn => "blend_color_burn",
+            BlendMode::HardLight => "blend_hard_light",
+            BlendMode::SoftLight => "blend_soft_light",
+            BlendMode::Difference => "blend_difference",
+            BlendMode::Exclusion => "blend_exclusion",
+            BlendMode::Add => "blend_add",
+            BlendMode::Subtract => "blend_subtract",
+            BlendMode::Hue => "blend_hue",
+            BlendMode::Saturation => "blend_saturation",
+            BlendMode::Color => "blend_color",
+            BlendMode::Luminosity => "blend_luminosity",
+        }
+    }
+    
+    // Mix straight (non premultiplied) backdrop and source colors
+    fn mix(&self, cb: [f32; 3], cs: [f32; 3]) -> [f32; 3] {
+        match self {
+            BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+            BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+            BlendMode::Color => set_lum(cs, lum(cb)),
+            BlendMode::Luminosity => set_lum(cb, lum(cs)),
+            _ => [
+                self.mix_channel(cb[0], cs[0]),
+                self.mix_channel(cb[1], cs[1]),
+                self.mix_channel(cb[2], cs[2]),
+            ],
+        }
+    }
+    
+    // Separable blend modes work on each channel independently
+    fn mix_channel(&self, cb: f32, cs: f32) -> f32 {
+        match self {
+            BlendMode::Multiply => cb * cs,
+            BlendMode::Screen => cb + cs - cb * cs,
+            BlendMode::Overlay => BlendMode::HardLight.mix_channel(cs, cb),
+            BlendMode::Darken => cb.min(cs),
+            BlendMode::Lighten => cb.max(cs),
+            BlendMode::ColorDodge => {
+                if cb <= 0.0 {
+                    0.0
+                } else if cs >= 1.0 {
+                    1.0
+                } else {
+                    (cb / (1.0 - cs)).min(1.0)
+                }
+            },
+            BlendMode::ColorBurn => {
+                if cb >= 1.0 {
+                    1.0
+                } else if cs <= 0.0 {
+                    0.0
+                } else {
+                    1.0 - ((1.0 - cb) / cs).min(1.0)
+                }
+            },
+            BlendMode::HardLight => {
+                if cs <= 0.5 {
+                    cb * 2.0 * cs
+                } else {
+                    let s = 2.0 * cs - 1.0;
+                    cb + s - cb * s
+                }
+            },
+            BlendMode::SoftLight => {
+                if cs <= 0.5 {
+                    cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+                } else {
+                    let d = if cb <= 0.25 {
+                        ((16.0 * cb - 12.0) * cb + 4.0) * cb
+                    } else {
+                        cb.sqrt()
+                    };
+                    cb + (2.0 * cs - 1.0) * (d - cb)
+                }
+            },
+            BlendMode::Difference => (cb - cs).abs(),
+            BlendMode::Exclusion => cb + cs - 2.0 * cb * cs,
+            BlendMode::Add => (cb + cs).min(1.0),
+            BlendMode::Subtract => (cb - cs).max(0.0),
+            _ => cs,
+        }
+    }
+}
+
+// Helpers for the non-separable (HSL) blend modes
+fn lum(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+fn clip_color(c: [f32; 3]) -> [f32; 3] {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut result = c;
+    for channel in result.iter_mut() {
+        if n < 0.0 {
+            *channel = l + (*channel - l) * l / (l - n);
+        }
+        if x > 1.0 {
+            *channel = l + (*channel - l) * (1.0 - l) / (x - l);
+        }
+    }
+    result
+}
+
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(c);
+    clip_color([c[0] + d, c[1] + d, c[2] + d])
+}
+
+fn sat(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_sat(c: [f32; 3], s: f32) -> [f32; 3] {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    if max <= min {
+        return [0.0; 3];
+    }
+    let mut result = [0.0; 3];
+    for i in 0..3 {
+        result[i] = (c[i] - min) * s / (max - min);
+    }
+    result
+}
+
+// Blend a source faded by `coverage`, opaque normal sources simply replace the backdrop
+#[inline]
+pub fn blend_with_coverage(dst: Rgba, src: Rgba, coverage: f32, mode: BlendMode) -> Rgba {
+    if src[3] >= 1.0 && coverage >= 1.0 && mode == BlendMode::Normal {
+        src
+    } else {
+        blend(dst, scale(src, coverage), mode)
+    }
+}
+
+// Composite a premultiplied source over a premultiplied backdrop with the given blend mode
+#[inline]
+pub fn blend(dst: Rgba, src: Rgba, mode: BlendMode) -> Rgba {
+    if mode == BlendMode::Normal || dst[3] <= 0.0 || src[3] <= 0.0 {
+        return source_over(dst, src);
+    }
+    
+    let ab = dst[3];
+    let a_s = src[3];
+    let cb = [dst[0] / ab, dst[1] / ab, dst[2] / ab];
+    let cs = [src[0] / a_s, src[1] / a_s, src[2] / a_s];
+    let mixed = mode.mix(cb, cs);
+    
+    let mut result = [0.0; 4];
+    for i in 0..3 {
+        result[i] = src[i] * (1.0 - ab) + dst[i] * (1.0 - a_s) + a_s * ab * mixed[i].clamp(0.0, 1.0);
+    }
+    result[3] = a_s + ab * (1.0 - a_s);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Rgba, expected: Rgba) {
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-5), "{actual:?} != {expected:?}");
+    }
+
+    #[test]
+    fn source_over_stacks_premultiplied_colors() {
+        let blue = [0.0, 0.0, 1.0, 1.0];
+        let half_red = [0.5, 0.0, 0.0, 0.5];
+        assert_close(source_over(blue, half_red), [0.5, 0.0, 0.5, 1.0]);
+        assert_close(source_over(half_red, half_red), [0.75, 0.0, 0.0, 0.75]);
+        assert_eq!(source_over(blue, TRANSPARENT), blue);
+        assert_eq!(source_over(TRANSPARENT, half_red), half_red);
+    }
+
+    #[test]
+    fn opaque_colors_replace_the_backdrop() {
+        let blue = [0.0, 0.0, 1.0, 1.0];
+        let red = [1.0, 0.0, 0.0, 1.0];
+        let half_red = [0.5, 0.0, 0.0, 0.5];
+        assert_eq!(blend_colors(None, half_red), half_red);
+        assert_eq!(blend_colors(Some(blue), red), red);
+        assert_eq!(blend_colors(Some(blue), half_red), source_over(blue, half_red));
+    }
+
+    #[test]
+    fn premultiply_round_trip() {
+        let color = [0.2, 0.6, 1.0, 0.5];
+        assert_close(premultiply(color), [0.1, 0.3, 0.5, 0.5]);
+        assert_close(unpremultiply(premultiply(color)), color);
+        assert_eq!(unpremultiply([0.3, 0.3, 0.3, 0.0]), TRANSPARENT);
+    }
+
+    #[test]
+    fn srgb_transfer_round_trip() {
+        for v in [0.0, 0.002, 0.04, 0.5, 1.0] {
+            assert!((linear_to_srgb(srgb_to_linear(v)) - v).abs() < 1e-5);
+        }
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+    }
+
+    #[test]
+    fn transparent_sides_skip_the_mix() {
+        let dst = [0.1, 0.2, 0.3, 0.5];
+        let src = [0.4, 0.2, 0.0, 0.8];
+        for mode in BlendMode::ALL {
+            assert_eq!(blend(dst, TRANSPARENT, mode), dst);
+            assert_eq!(blend(TRANSPARENT, src, mode), src);
+        }
+    }
+
+    #[test]
+    fn opaque_separable_modes() {
+        let cb = [0.5, 0.4, 0.2, 1.0];
+        let cs = [0.5, 1.0, 0.0, 1.0];
+        assert_close(blend(cb, cs, BlendMode::Normal), cs);
+        assert_close(blend(cb, cs, BlendMode::Multiply), [0.25, 0.4, 0.0, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Screen), [0.75, 1.0, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Darken), [0.5, 0.4, 0.0, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Lighten), [0.5, 1.0, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Difference), [0.0, 0.6, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Exclusion), [0.5, 0.6, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Add), [1.0, 1.0, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Subtract), [0.0, 0.0, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::HardLight), [0.5, 1.0, 0.0, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::Overlay), [0.5, 0.8, 0.0, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::ColorDodge), [1.0, 1.0, 0.2, 1.0]);
+        assert_close(blend(cb, cs, BlendMode::ColorBurn), [0.0, 0.4, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn partial_alpha_follows_the_compositing_formula() {
+        // Co = cs * (1 - ab) + cb * (1 - as) + as * ab * B(cb, cs) on premultiplied colors
+        let half_white = [0.5, 0.5, 0.5, 0.5];
+        assert_close(blend(half_white, half_white, BlendMode::Multiply), [0.75, 0.75, 0.75, 0.75]);
+        let gray = [0.5, 0.5, 0.5, 1.0];
+        let half_gray = [0.25, 0.25, 0.25, 0.5];
+        assert_close(blend(gray, half_gray, BlendMode::Multiply), [0.375, 0.375, 0.375, 1.0]);
+        assert_close(blend(gray, half_gray, BlendMode::Normal), source_over(gray, half_gray));
+    }
+
+    #[test]
+    fn non_separable_modes_on_grays() {
+        let cb = [0.2, 0.2, 0.2, 1.0];
+        let cs = [0.8, 0.8, 0.8, 1.0];
+        // Grays have no hue or saturation to give
+        assert_close(blend(cb, cs, BlendMode::Hue), cb);
+        assert_close(blend(cb, cs, BlendMode::Saturation), cb);
+        assert_close(blend(cb, cs, BlendMode::Color), cb);
+        assert_close(blend(cb, cs, BlendMode::Luminosity), cs);
+        // A red keeps its hue and takes the luminosity of the backdrop
+        let red = [1.0, 0.0, 0.0, 1.0];
+        let mixed = blend(cs, red, BlendMode::Color);
+        assert!(mixed[0] > mixed[1] && (mixed[1] - mixed[2]).abs() < 1e-5);
+        assert!((lum([mixed[0], mixed[1], mixed[2]]) - 0.8).abs() < 1e-5);
+    }
+
+    #[test]
+    fn coverage_fades_the_source() {
+        let dst = [0.0, 0.0, 1.0, 1.0];
+        let src = [1.0, 0.0, 0.0, 1.0];
+        assert_eq!(blend_with_coverage(dst, src, 1.0, BlendMode::Normal), src);
+        assert_close(blend_with_coverage(dst, src, 0.25, BlendMode::Normal), [0.25, 0.0, 0.75, 1.0]);
+        assert_close(blend_with_coverage(dst, src, 0.0, BlendMode::Multiply), dst);
+    }
+}
@@ -0,0 +1,492 @@
+use serde::{Serialize, Deserialize};
+
+use crate::shapes::ShapeKind;
+use crate::transform::Orientation;
+
+// What an undo step did, used to name it in the history panel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HistoryAction {
+    BrushStroke,
+    Erase,
+    Fill,
+    Line,
+    Shape(ShapeKind),
+    Curve,
+    Polyline,
+    AddLayer,
+    GroupLayer,
+    RemoveLayer,
+    ReorderLayers,
+    RenameLayer,
+    LayerVisibility,
+    LayerOpacity,
+    BlendMode,
+    Clipping,
+    LayerLocks,
+    CollapseGroup,
+    DuplicateLayer,
+    AddAdjustment,
+    AdjustLayer,
+    MergeDown,
+    MergeVisible,
+    Flatten,
+    AddMask,
+    ToggleMask,
+    ApplyMask,
+    DeleteMask,
+    MoveLayer,
+    CanvasSize,
+    Crop,
+    Trim,
+    Scale,
+    Orient(Orientation),
+    Rotate,
+}
+
+impl HistoryAction {
+    // Localization key of the step name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            HistoryAction::BrushStroke => "step_brush",
+            HistoryAction::Erase => "step_erase",
+            HistoryAction::Fill => "step_fill",
+            HistoryAction::Line => "step_line",
+            HistoryAction::Shape(kind) => kind.text_key(),
+            HistoryAction::Curve => "step_curve",
+            HistoryAction::Polyline => "step_polyline",
+            HistoryAction::AddLayer => "step_add_layer",
+            HistoryAction::GroupLayer => "step_group_layer",
+            HistoryAction::RemoveLayer => "step_remove_layer",
+            HistoryAction::ReorderLayers => "step_reorder_layers",
+            HistoryAction::RenameLayer => "step_rename_layer",
+            HistoryAction::LayerVisibility => "step_layer_visibility",
+            HistoryAction::LayerOpacity => "step_layer_opacity",
+            HistoryAction::BlendMode => "step_blend_mode",
+            HistoryAction::Clipping => "step_clipping",
+            HistoryAction::LayerLocks => "step_layer_locks",
+            HistoryAction::CollapseGroup => "step_collapse_group",
+            HistoryAction::DuplicateLayer => "step_duplicate_layer",
+            HistoryAction::AddAdjustment => "step_add_adjustment",
+            HistoryAction::AdjustLayer => "step_adjust_layer",
+            HistoryAction::MergeDown => "step_merge_down",
+            HistoryAction::MergeVisible => "step_merge_visible",
+            HistoryAction::Flatten => "step_flatten",
+            HistoryAction::AddMask => "step_add_mask",
+            HistoryAction::ToggleMask => "step_toggle_mask",
+            HistoryAction::ApplyMask => "step_apply_mask",
+            HistoryAction::DeleteMask => "step_delete_mask",
+            HistoryAction::MoveLayer => "step_move_layer",
+            HistoryAction::CanvasSize => "step_canvas_size",
+            HistoryAction::Crop => "step_crop",
+            HistoryAction::Trim => "step_trim",
+            HistoryAction::Scale => "step_scale",
+            HistoryAction::Orient(orientation) => orientation.text_key(),
+            HistoryAction::Rotate => "step_rotate",
+        }
+    }
+}
+
+// Deflate level of the tiles kept by the history, fast since it runs when a stroke ends.
+// Flat fills and strokes over empty tiles still shrink to a few hundred bytes.
+const PACK_LEVEL: u8 = 1;
+
+// Compress the raw content of a tile
+pub fn pack(bytes: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec(bytes, PACK_LEVEL)
+}
+
+pub fn unpack(packed: &[u8]) -> Option<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec(packed).ok()
+}
+
+// Standard base64, used to write packed tiles in .rustiq files
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+// None when the text isn't valid base64
+pub fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() < 2 {
+            return None;
+        }
+        let mut group = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            group |= (value as u32) << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((group >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// Step of the undo tree with the step it was made after, `None` being the start
+struct HistoryNode<T> {
+    value: T,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    // Child redo goes to, the last one made or visited
+    next: Option<usize>,
+}
+
+// Undo tree, a new step made after an undo starts a branch next to the undone steps
+// instead of discarding them. Steps are numbered in the order they were made.
+pub struct HistoryTree<T> {
+    nodes: Vec<HistoryNode<T>>,
+    // Steps made from the start
+    roots: Vec<usize>,
+    start_next: Option<usize>,
+    // Last applied step, `None` when everything is undone
+    current: Option<usize>,
+}
+
+impl<T> Default for HistoryTree<T> {
+    fn default() -> Self {
+        HistoryTree {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            start_next: None,
+            current: None,
+        }
+    }
+}
+
+impl<T> HistoryTree<T> {
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn get(&self, node: usize) -> &T {
+        &self.nodes[node].value
+    }
+
+    pub fn get_mut(&mut self, node: usize) -> &mut T {
+        &mut self.nodes[node].value
+    }
+
+    pub fn parent(&self, node: usize) -> Option<usize> {
+        self.nodes[node].parent
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().map(|node| &node.value)
+    }
+
+    fn next(&self, node: Option<usize>) -> Option<usize> {
+        match node {
+            Some(node) => self.nodes[node].next,
+            None => self.start_next,
+        }
+    }
+
+    fn set_next(&mut self, node: Option<usize>, next: Option<usize>) {
+        match node {
+            Some(node) => self.nodes[node].next = next,
+            None => self.start_next = next,
+        }
+    }
+
+    fn children(&self, node: Option<usize>) -> &[usize] {
+        match node {
+            Some(node) => &self.nodes[node].children,
+            None => &self.roots,
+        }
+    }
+
+    // Add a step after `parent` without moving to it
+    pub fn insert(&mut self, parent: Option<usize>, value: T) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(HistoryNode { value, parent, children: Vec::new(), next: None });
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(node),
+            None => self.roots.push(node),
+        }
+        self.set_next(parent, Some(node));
+        node
+    }
+
+    // Add a step after the current one and move to it
+    pub fn push(&mut self, value: T) -> usize {
+        let node = self.insert(self.current, value);
+        self.current = Some(node);
+        node
+    }
+
+    // Move back to the parent and return the step to undo
+    pub fn step_back(&mut self) -> Option<usize> {
+        let node = self.current?;
+        self.current = self.nodes[node].parent;
+        self.set_next(self.current, Some(node));
+        Some(node)
+    }
+
+    // Move to the next step of the branch and return it to redo
+    pub fn step_forward(&mut self) -> Option<usize> {
+        let node = self.next(self.current)?;
+        self.current = Some(node);
+        Some(node)
+    }
+
+    // Whether `ancestor` is `node` or a step before it
+    pub fn is_ancestor(&self, ancestor: Option<usize>, node: Option<usize>) -> bool {
+        let mut node = node;
+        loop {
+            if node == ancestor {
+                return true;
+            }
+            match node {
+                Some(step) => node = self.nodes[step].parent,
+                None => return false,
+            }
+        }
+    }
+
+    // Steps from the start to `node`
+    pub fn path(&self, node: Option<usize>) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut node = node;
+        while let Some(step) = node {
+            path.push(step);
+            node = self.nodes[step].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    // Make redo follow the branch leading to `node`
+    pub fn select(&mut self, node: Option<usize>) {
+        for step in self.path(node) {
+            self.set_next(self.nodes[step].parent, Some(step));
+        }
+    }
+
+    // Make `node` the current step without replaying anything, for a rebuilt tree
+    pub fn move_to(&mut self, node: Option<usize>) {
+        self.select(node);
+        self.current = node;
+    }
+
+    // Steps with their indentation for a listing. The branch redo follows keeps the
+    // indentation of its parent, the others are indented and listed before it.
+    pub fn rows(&self) -> Vec<(usize, usize)> {
+        let mut rows = Vec::with_capacity(self.nodes.len());
+        let mut pending = Vec::new();
+        self.push_children(None, 0, &mut pending);
+        while let Some((node, depth)) = pending.pop() {
+            rows.push((node, depth));
+            self.push_children(Some(node), depth, &mut pending);
+        }
+        rows
+    }
+
+    fn push_children(&self, node: Option<usize>, depth: usize, pending: &mut Vec<(usize, usize)>) {
+        let next = self.next(node);
+        pending.extend(next.map(|next| (next, depth)));
+        for &child in self.children(node).iter().rev() {
+            if Some(child) != next {
+                pending.push((child, depth + 1));
+            }
+        }
+    }
+
+    // Drop the oldest steps that can go until the tree fits `budget`. Those are the ends
+    // of branches and the first applied step when nothing else starts from the start.
+    // The current step is always kept.
+    pub fn trim(&mut self, budget: usize, size: impl Fn(&T) -> usize) {
+        let mut total: usize = self.iter().map(&size).sum();
+        while total > budget {
+            let Some(node) = (0..self.nodes.len()).find(|&node| self.removable(node)) else {
+                break;
+            };
+            total -= size(&self.nodes[node].value);
+            self.remove(node);
+        }
+    }
+
+    fn removable(&self, node: usize) -> bool {
+        if Some(node) == self.current {
+            return false;
+        }
+        let step = &self.nodes[node];
+        step.children.is_empty()
+            || (step.parent.is_none() && self.roots.len() == 1 && self.is_ancestor(Some(node), self.current))
+    }
+
+    fn remove(&mut self, node: usize) {
+        let parent = self.nodes[node].parent;
+        if self.nodes[node].children.is_empty() {
+            let siblings = match parent {
+                Some(parent) => &mut self.nodes[parent].children,
+                None => &mut self.roots,
+            };
+            siblings.retain(|&child| child != node);
+            let last = siblings.last().copied();
+            if self.next(parent) == Some(node) {
+                self.set_next(parent, last);
+            }
+        } else {
+            // The start becomes the state after the removed step
+            let children = std::mem::take(&mut self.nodes[node].children);
+            for &child in &children {
+                self.nodes[child].parent = None;
+            }
+            self.roots = children;
+            self.start_next = self.nodes[node].next;
+        }
+        self.nodes.remove(node);
+
+        // Following steps move down by one
+        let shift = |index: usize| index - usize::from(index > node);
+        for step in &mut self.nodes {
+            step.parent = step.parent.map(shift);
+            step.next = step.next.map(shift);
+            step.children.iter_mut().for_each(|child| *child = shift(*child));
+        }
+        self.roots.iter_mut().for_each(|root| *root = shift(*root));
+        self.start_next = self.start_next.map(shift);
+        self.current = self.current.map(shift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        let cases: [(&[u8], &str); 5] = [(b"", ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foobar", "Zm9vYmFy")];
+        for (bytes, text) in cases {
+            assert_eq!(to_base64(bytes), text);
+            assert_eq!(from_base64(text).as_deref(), Some(bytes));
+        }
+        // Padding can be left out
+        assert_eq!(from_base64("Zm8").as_deref(), Some(&b"fo"[..]));
+    }
+
+    #[test]
+    fn base64_invalid() {
+        assert_eq!(from_base64("Zm9v!A"), None);
+        assert_eq!(from_base64("Zm-v"), None);
+        // A lone character can't hold a byte
+        assert_eq!(from_base64("Zm9vY"), None);
+        assert_eq!(from_base64("Z==="), None);
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let bytes: Vec<u8> = (0..4096).map(|i| (i / 64) as u8).collect();
+        let packed = pack(&bytes);
+        assert!(packed.len() < bytes.len());
+        assert_eq!(unpack(&packed), Some(bytes));
+        assert_eq!(unpack(&[0xFF, 0xFF, 0xFF, 0xFF]), None);
+    }
+
+    // Steps a, b undone, then c: the start leads to a, which has b and c after it
+    fn branched() -> HistoryTree<char> {
+        let mut tree = HistoryTree::default();
+        tree.push('a');
+        tree.push('b');
+        tree.step_back();
+        tree.push('c');
+        tree
+    }
+
+    #[test]
+    fn undo_goes_back_along_the_branch() {
+        let mut tree = branched();
+        assert_eq!(tree.current(), Some(2));
+        assert_eq!(tree.step_back(), Some(2));
+        assert_eq!(tree.step_back(), Some(0));
+        assert_eq!(tree.step_back(), None);
+        assert_eq!(tree.step_forward(), Some(0));
+        assert_eq!(tree.step_forward(), Some(2));
+        assert_eq!(tree.step_forward(), None);
+    }
+
+    #[test]
+    fn rows_list_other_branches_first() {
+        let mut tree = branched();
+        assert_eq!(tree.rows(), vec![(0, 0), (1, 1), (2, 0)]);
+        tree.select(Some(1));
+        assert_eq!(tree.rows(), vec![(0, 0), (2, 1), (1, 0)]);
+        assert_eq!(tree.path(Some(1)), vec![0, 1]);
+        assert!(tree.is_ancestor(Some(0), Some(2)));
+        assert!(!tree.is_ancestor(Some(1), Some(2)));
+    }
+
+    #[test]
+    fn trim_reroots_on_the_first_step() {
+        let mut tree = HistoryTree::default();
+        for value in ['a', 'b', 'c'] {
+            tree.push(value);
+        }
+        tree.trim(2, |_| 1);
+        assert_eq!(tree.iter().copied().collect::<Vec<_>>(), vec!['b', 'c']);
+        assert_eq!(tree.parent(0), None);
+        assert_eq!(tree.parent(1), Some(0));
+        assert_eq!(tree.current(), Some(1));
+        assert_eq!(tree.rows(), vec![(0, 0), (1, 0)]);
+        assert_eq!(tree.step_back(), Some(1));
+        assert_eq!(tree.step_back(), Some(0));
+        assert_eq!(tree.step_back(), None);
+    }
+
+    #[test]
+    fn trim_removes_branch_ends_and_shifts_indices() {
+        // a undone, then b and c: a is a branch end next to b
+        let mut tree = HistoryTree::default();
+        tree.push('a');
+        tree.step_back();
+        tree.push('b');
+        tree.push('c');
+        tree.trim(2, |_| 1);
+        assert_eq!(tree.iter().copied().collect::<Vec<_>>(), vec!['b', 'c']);
+        assert_eq!(tree.parent(1), Some(0));
+        assert_eq!(tree.current(), Some(1));
+        assert_eq!(tree.rows(), vec![(0, 0), (1, 0)]);
+        tree.move_to(None);
+        assert_eq!(tree.step_forward(), Some(0));
+        assert_eq!(tree.step_forward(), Some(1));
+    }
+
+    #[test]
+    fn trim_keeps_the_current_step() {
+        let mut tree = HistoryTree::default();
+        tree.push('a');
+        tree.trim(0, |_| 1);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.current(), Some(0));
+    }
+}
@@ -20,20 +20,88 @@ fn get_french_text(key: &str) -> String {
         ("canvas_dimensions", "Dimensions du canevas"),
         ("width", "Largeur:"),
         ("height", "Hauteur:"),
+        ("bit_depth", "Profondeur:"),
+        ("depth_8", "8 bits"),
+        ("depth_16", "16 bits"),
+        ("depth_float", "Flottant"),
         ("create_new_canvas", "Créer un nouveau canevas"),
         ("open_png", "Ouvrir un fichier PNG"),
         ("open_rustiq", "Ouvrir un fichier Rustiq"),
         ("open_file", "Ouvrir un fichier"),
+        ("convert_profile_on_open", "Convertir les profils de couleur en sRGB à l'ouverture"),
         
         // Onglets et panneaux
         ("layers", "Calques"),
         ("tools", "Outils"),
         ("save_options", "Options de sauvegarde"),
+        ("include_history", "Inclure l'historique"),
+        ("include_history_hint", "Enregistre les étapes d'annulation dans les fichiers .rustiq"),
         
         // Boutons de calques
         ("layer", "Calque"),
         ("up", "Haut"),
         ("down", "Bas"),
+        ("group", "Groupe"),
+        ("duplicate_layer", "Dupliquer le calque"),
+        ("copy", "copie"),
+        ("merge_down", "Fusionner avec le calque inférieur"),
+        ("merge_visible", "Fusionner visibles"),
+        ("merged", "Fusion"),
+        ("flatten", "Aplatir"),
+        ("background", "Arrière-plan"),
+        ("new_adjustment", "Nouveau calque de réglage"),
+        
+        // Réglages
+        ("adj_brightness_contrast", "Luminosité/Contraste"),
+        ("adj_hue_saturation", "Teinte/Saturation"),
+        ("adj_levels", "Niveaux"),
+        ("adj_curves", "Courbes"),
+        ("adj_invert", "Négatif"),
+        ("adj_threshold", "Seuil"),
+        ("brightness", "Luminosité"),
+        ("contrast", "Contraste"),
+        ("hue", "Teinte"),
+        ("saturation", "Saturation"),
+        ("lightness", "Luminosité"),
+        ("black_point", "Noir"),
+        ("white_point", "Blanc"),
+        ("gamma", "Gamma"),
+        ("threshold_level", "Seuil"),
+        ("mask", "Masque:"),
+        ("add_mask", "Ajouter un masque"),
+        ("enable_mask", "Activer le masque"),
+        ("edit_mask", "Éditer"),
+        ("apply_mask", "Appliquer"),
+        ("delete_mask", "Supprimer le masque"),
+        ("lock", "Verrou:"),
+        ("lock_alpha", "Verrouiller la transparence"),
+        ("lock_pixels", "Verrouiller les pixels"),
+        ("lock_position", "Verrouiller la position"),
+        ("lock_all", "Tout verrouiller"),
+        ("layer_locked", "Calque verrouillé: modifications refusées"),
+        ("opacity", "Opacité:"),
+        ("blend_mode", "Fusion:"),
+        ("clip_to_below", "Écrêter sur le calque inférieur"),
+        
+        // Modes de fusion
+        ("blend_normal", "Normal"),
+        ("blend_multiply", "Produit"),
+        ("blend_screen", "Superposition"),
+        ("blend_overlay", "Incrustation"),
+        ("blend_darken", "Obscurcir"),
+        ("blend_lighten", "Éclaircir"),
+        ("blend_color_dodge", "Densité couleur -"),
+        ("blend_color_burn", "Densité couleur +"),
+        ("blend_hard_light", "Lumière crue"),
+        ("blend_soft_light", "Lumière tamisée"),
+        ("blend_difference", "Différence"),
+        ("blend_exclusion", "Exclusion"),
+        ("blend_add", "Addition"),
+        ("blend_subtract", "Soustraction"),
+        ("blend_hue", "Teinte"),
+        ("blend_saturation", "Saturation"),
+        ("blend_color", "Couleur"),
+        ("blend_luminosity", "Luminosité"),
         
         // Outils
         ("brush", "Pinceau"),
@@ -41,10 +109,27 @@ fn get_french_text(key: &str) -> String {
         ("paint_bucket", "Pot de peinture"),
         ("color_picker", "Pipette"),
         ("line", "Ligne"),
+        ("curve", "Courbe"),
+        ("polyline", "Ligne brisée"),
+        ("shape_rectangle", "Rectangle"),
+        ("shape_rounded_rectangle", "Rectangle arrondi"),
+        ("shape_ellipse", "Ellipse"),
+        ("shape_polygon", "Polygone"),
+        ("select", "Sélection"),
+        ("move", "Déplacement"),
         
         // Options
         ("brush_size", "Taille du pinceau:"),
         ("eraser_size", "Taille de la gomme:"),
+        ("shape_style", "Style de forme:"),
+        ("shape_outline", "Contour"),
+        ("shape_fill", "Remplissage"),
+        ("shape_outline_fill", "Contour et remplissage"),
+        ("corner_radius", "Rayon des coins:"),
+        ("polygon_sides", "Nombre de côtés:"),
+        ("shape_hint", "Maj: côtés égaux, Alt: depuis le centre"),
+        ("curve_hint", "Glisser: poignées, Alt: poignées indépendantes"),
+        ("path_hint", "Entrée ou Échap: tracer, Retour arrière: retirer le dernier point"),
         ("colors", "Couleurs:"),
         ("primary", "Primaire:"),
         ("secondary", "Secondaire:"),
@@ -60,6 +145,63 @@ fn get_french_text(key: &str) -> String {
         ("return_to_menu", "Retour au menu"),
         ("undo", "Annuler"),
         ("redo", "Refaire"),
+        ("history", "Historique"),
+        ("history_start", "État initial"),
+        ("history_memory", "Mémoire max:"),
+        ("history_used", "Utilisée:"),
+        ("megabytes", "Mo"),
+        ("step_brush", "Coup de pinceau"),
+        ("step_erase", "Gomme"),
+        ("step_fill", "Remplissage"),
+        ("step_line", "Ligne"),
+        ("step_curve", "Courbe"),
+        ("step_polyline", "Ligne brisée"),
+        ("step_add_layer", "Ajouter un calque"),
+        ("step_group_layer", "Grouper"),
+        ("step_remove_layer", "Supprimer le calque"),
+        ("step_reorder_layers", "Déplacer dans la pile"),
+        ("step_rename_layer", "Renommer le calque"),
+        ("step_layer_visibility", "Afficher/masquer le calque"),
+        ("step_layer_opacity", "Opacité du calque"),
+        ("step_blend_mode", "Mode de fusion"),
+        ("step_clipping", "Masque d'écrêtage"),
+        ("step_layer_locks", "Verrous du calque"),
+        ("step_collapse_group", "Replier/déplier le groupe"),
+        ("step_duplicate_layer", "Dupliquer le calque"),
+        ("step_add_adjustment", "Ajouter un réglage"),
+        ("step_adjust_layer", "Modifier le réglage"),
+        ("step_merge_down", "Fusionner avec le calque inférieur"),
+        ("step_merge_visible", "Fusionner visibles"),
+        ("step_flatten", "Aplatir l'image"),
+        ("step_add_mask", "Ajouter un masque"),
+        ("step_toggle_mask", "Activer/désactiver le masque"),
+        ("step_apply_mask", "Appliquer le masque"),
+        ("step_delete_mask", "Supprimer le masque"),
+        ("step_move_layer", "Déplacement"),
+        ("step_canvas_size", "Taille du canevas"),
+        ("step_crop", "Recadrage"),
+        ("step_trim", "Rognage"),
+        ("step_scale", "Redimensionner l'image"),
+        ("step_rotate", "Rotation"),
+        ("image", "Image"),
+        ("canvas_size", "Taille du canevas"),
+        ("scale_image", "Redimensionner l'image"),
+        ("crop_to_selection", "Recadrer sur la sélection"),
+        ("trim", "Rogner les bords transparents"),
+        ("flip_horizontal", "Retourner horizontalement"),
+        ("flip_vertical", "Retourner verticalement"),
+        ("rotate_90", "Rotation 90° horaire"),
+        ("rotate_180", "Rotation 180°"),
+        ("rotate_270", "Rotation 90° antihoraire"),
+        ("rotate_free", "Rotation libre..."),
+        ("color_profile", "Profil colorimétrique"),
+        ("export_profile", "Profil d'export:"),
+        ("use_srgb", "Utiliser sRGB"),
+        ("load_color_profile", "Charger un profil ICC..."),
+        ("unsupported_color_profile", "Profil colorimétrique non pris en charge"),
+        ("linear_blending", "Mélange en lumière linéaire"),
+        ("rotate_image", "Faire pivoter l'image"),
+        ("rotate_layer", "Faire pivoter le calque"),
         ("save_png", "Sauvegarder PNG"),
         ("save_rustiq", "Sauvegarder Rustiq"),
         ("save_file", "Sauvegarder Fichier"),
@@ -76,6 +218,14 @@ fn get_french_text(key: &str) -> String {
         ("no", "Non"),
         ("cancel", "Annuler"),
         ("rename_layer", "Renommer le calque"),
+        ("anchor", "Ancrage:"),
+        ("keep_ratio", "Conserver les proportions"),
+        ("interpolation", "Interpolation:"),
+        ("angle", "Angle:"),
+        ("filter_nearest", "Plus proche voisin"),
+        ("filter_bilinear", "Bilinéaire"),
+        ("filter_bicubic", "Bicubique"),
+        ("filter_lanczos", "Lanczos"),
         
         // Erreurs
         ("format_not_supported", "Format de fichier non pris en charge"),
@@ -98,20 +248,88 @@ fn get_english_text(key: &str) -> String {
         ("canvas_dimensions", "Canvas Dimensions"),
         ("width", "Width:"),
         ("height", "Height:"),
+        ("bit_depth", "Bit depth:"),
+        ("depth_8", "8-bit"),
+        ("depth_16", "16-bit"),
+        ("depth_float", "Float"),
         ("create_new_canvas", "Create New Canvas"),
         ("open_png", "Open PNG File"),
         ("open_rustiq", "Open Rustiq File"),
         ("open_file", "Open File"),
+        ("convert_profile_on_open", "Convert color profiles to sRGB on open"),
         
         // Tabs and panels
         ("layers", "Layers"),
         ("tools", "Tools"),
         ("save_options", "Save Options"),
+        ("include_history", "Include history"),
+        ("include_history_hint", "Save the undo steps in .rustiq files"),
         
         // Layer buttons
         ("layer", "Layer"),
         ("up", "Up"),
         ("down", "Down"),
+        ("group", "Group"),
+        ("duplicate_layer", "Duplicate layer"),
+        ("copy", "copy"),
+        ("merge_down", "Merge down"),
+        ("merge_visible", "Merge Visible"),
+        ("merged", "Merged"),
+        ("flatten", "Flatten"),
+        ("background", "Background"),
+        ("new_adjustment", "New Adjustment Layer"),
+        
+        // Adjustments
+        ("adj_brightness_contrast", "Brightness/Contrast"),
+        ("adj_hue_saturation", "Hue/Saturation"),
+        ("adj_levels", "Levels"),
+        ("adj_curves", "Curves"),
+        ("adj_invert", "Invert"),
+        ("adj_threshold", "Threshold"),
+        ("brightness", "Brightness"),
+        ("contrast", "Contrast"),
+        ("hue", "Hue"),
+        ("saturation", "Saturation"),
+        ("lightness", "Lightness"),
+        ("black_point", "Black"),
+        ("white_point", "White"),
+        ("gamma", "Gamma"),
+        ("threshold_level", "Level"),
+        ("mask", "Mask:"),
+        ("add_mask", "Add Mask"),
+        ("enable_mask", "Enable mask"),
+        ("edit_mask", "Edit"),
+        ("apply_mask", "Apply"),
+        ("delete_mask", "Delete mask"),
+        ("lock", "Lock:"),
+        ("lock_alpha", "Lock transparency"),
+        ("lock_pixels", "Lock pixels"),
+        ("lock_position", "Lock position"),
+        ("lock_all", "Lock all"),
+        ("layer_locked", "Layer locked: edits are refused"),
+        ("opacity", "Opacity:"),
+        ("blend_mode", "Blend:"),
+        ("clip_to_below", "Clip to layer below"),
+        
+        // Blend modes
+        ("blend_normal", "Normal"),
+        ("blend_multiply", "Multiply"),
+        ("blend_screen", "Screen"),
+        ("blend_overlay", "Overlay"),
+        ("blend_darken", "Darken"),
+        ("blend_lighten", "Lighten"),
+        ("blend_color_dodge", "Color Dodge"),
+        ("blend_color_burn", "Color Burn"),
+        ("blend_hard_light", "Hard Light"),
+        ("blend_soft_light", "Soft Light"),
+        ("blend_difference", "Difference"),
+        ("blend_exclusion", "Exclusion"),
+        ("blend_add", "Add"),
+        ("blend_subtract", "Subtract"),
+        ("blend_hue", "Hue"),
+        ("blend_saturation", "Saturation"),
+        ("blend_color", "Color"),
+        ("blend_luminosity", "Luminosity"),
         
         // Tools
         ("brush", "Brush"),
@@ -119,10 +337,27 @@ fn get_english_text(key: &str) -> String {
         ("paint_bucket", "Paint Bucket"),
         ("color_picker", "Color Picker"),
         ("line", "Line"),
+        ("curve", "Curve"),
+        ("polyline", "Polyline"),
+        ("shape_rectangle", "Rectangle"),
+        ("shape_rounded_rectangle", "Rounded rectangle"),
+        ("shape_ellipse", "Ellipse"),
+        ("shape_polygon", "Polygon"),
+        ("select", "Select"),
+        ("move", "Move"),
         
         // Options
         ("brush_size", "Brush Size:"),
         ("eraser_size", "Eraser Size:"),
+        ("shape_style", "Shape style:"),
+        ("shape_outline", "Outline"),
+        ("shape_fill", "Fill"),
+        ("shape_outline_fill", "Outline and fill"),
+        ("corner_radius", "Corner radius:"),
+        ("polygon_sides", "Sides:"),
+        ("shape_hint", "Shift: equal sides, Alt: from the center"),
+        ("curve_hint", "Drag: handles, Alt: independent handles"),
+        ("path_hint", "Enter or Escape: draw, Backspace: remove the last point"),
         ("colors", "Colors:"),
         ("primary", "Primary:"),
         ("secondary", "Secondary:"),
@@ -138,6 +373,63 @@ fn get_english_text(key: &str) -> String {
         ("return_to_menu", "Return to Menu"),
         ("undo", "Undo"),
         ("redo", "Redo"),
+        ("history", "History"),
+        ("history_start", "Initial state"),
+        ("history_memory", "Memory limit:"),
+        ("history_used", "Used:"),
+        ("megabytes", "MB"),
+        ("step_brush", "Brush stroke"),
+        ("step_erase", "Erase"),
+        ("step_fill", "Fill"),
+        ("step_line", "Line"),
+        ("step_curve", "Curve"),
+        ("step_polyline", "Polyline"),
+        ("step_add_layer", "Add layer"),
+        ("step_group_layer", "Group layer"),
+        ("step_remove_layer", "Delete layer"),
+        ("step_reorder_layers", "Reorder layers"),
+        ("step_rename_layer", "Rename layer"),
+        ("step_layer_visibility", "Show/hide layer"),
+        ("step_layer_opacity", "Layer opacity"),
+        ("step_blend_mode", "Blend mode"),
+        ("step_clipping", "Clipping mask"),
+        ("step_layer_locks", "Layer locks"),
+        ("step_collapse_group", "Collapse/expand group"),
+        ("step_duplicate_layer", "Duplicate layer"),
+        ("step_add_adjustment", "Add adjustment"),
+        ("step_adjust_layer", "Edit adjustment"),
+        ("step_merge_down", "Merge down"),
+        ("step_merge_visible", "Merge visible"),
+        ("step_flatten", "Flatten image"),
+        ("step_add_mask", "Add mask"),
+        ("step_toggle_mask", "Enable/disable mask"),
+        ("step_apply_mask", "Apply mask"),
+        ("step_delete_mask", "Delete mask"),
+        ("step_move_layer", "Move"),
+        ("step_canvas_size", "Canvas size"),
+        ("step_crop", "Crop"),
+        ("step_trim", "Trim"),
+        ("step_scale", "Scale image"),
+        ("step_rotate", "Rotate"),
+        ("image", "Image"),
+        ("canvas_size", "Canvas Size"),
+        ("scale_image", "Scale Image"),
+        ("crop_to_selection", "Crop to Selection"),
+        ("trim", "Trim Transparent Borders"),
+        ("flip_horizontal", "Flip Horizontal"),
+        ("flip_vertical", "Flip Vertical"),
+        ("rotate_90", "Rotate 90° Clockwise"),
+        ("rotate_180", "Rotate 180°"),
+        ("rotate_270", "Rotate 90° Counter-clockwise"),
+        ("rotate_free", "Free Rotation..."),
+        ("color_profile", "Color profile"),
+        ("export_profile", "Export profile:"),
+        ("use_srgb", "Use sRGB"),
+        ("load_color_profile", "Load ICC profile..."),
+        ("unsupported_color_profile", "Unsupported color profile"),
+        ("linear_blending", "Linear light blending"),
+        ("rotate_image", "Rotate Image"),
+        ("rotate_layer", "Rotate Layer"),
         ("save_png", "Save PNG"),
         ("save_rustiq", "Save Rustiq"),
         ("save_file", "Save File"),
@@ -154,6 +446,14 @@ fn get_english_text(key: &str) -> String {
         ("no", "No"),
         ("cancel", "Cancel"),
         ("rename_layer", "Rename Layer"),
+        ("anchor", "Anchor:"),
+        ("keep_ratio", "Keep aspect ratio"),
+        ("interpolation", "Interpolation:"),
+        ("angle", "Angle:"),
+        ("filter_nearest", "Nearest neighbour"),
+        ("filter_bilinear", "Bilinear"),
+        ("filter_bicubic", "Bicubic"),
+        ("filter_lanczos", "Lanczos"),
         
         // Errors
         ("format_not_supported", "File format not supported"),
@@ -1,27 +1,49 @@
 mod main_menu;
 mod localization;
+mod compositing;
+mod adjustments;
+mod tiles;
+mod transform;
+mod pixels;
+mod color_profile;
+mod history;
+mod shapes;
 
 use eframe::egui;
 use egui::{Color32, TextureHandle, TextureOptions, Rect, Pos2, Vec2, Stroke};
-use image::{ImageBuffer, Rgba, ImageFormat};
-use std::collections::VecDeque;
+use image::ImageFormat;
+use std::collections::{HashMap, HashSet, VecDeque};
 use rfd::FileDialog;
 use std::time::{Duration, Instant};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::io::Write;
+use std::ops::Range;
 use serde::{Serialize, Deserialize};
 
 use main_menu::MainMenu;
 use localization::{Language, get_text};
+use compositing::BlendMode;
+use adjustments::Adjustment;
+use tiles::{TileGrid, TileCoord, TILE_SIZE, TILE_AREA};
+use transform::{ResampleFilter, Orientation};
+use pixels::{BitDepth, PixelGrid};
+use color_profile::{ColorProfile, Samples};
+use history::{HistoryAction, HistoryTree};
+use shapes::{ShapeKind, ShapeStyle};
+use rayon::prelude::*;
 
 // Constants
-const MAX_UNDO_STEPS: usize = 20;
+// Memory the undo history may use before its oldest steps are dropped
+const DEFAULT_HISTORY_BUDGET: usize = 512 * MEGABYTE;
+const MEGABYTE: usize = 1024 * 1024;
 const SAVE_STATE_DELAY: Duration = Duration::from_millis(300);
 const CHECKERBOARD_SIZE: usize = 8;
 const WINDOW_WIDTH: f32 = 1200.0;
 const WINDOW_HEIGHT: f32 = 800.0;
 const MAX_SAVED_COLORS: usize = 16;
+// Version written in .rustiq files, see `RustiqueFile::version`
+const RUSTIQ_VERSION: u32 = 1;
 
 // Enum to represent different tools
 #[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
@@ -31,6 +53,13 @@ enum Tool {
     PaintBucket,
     ColorPicker,
     Line,
+    // Bezier curve placed anchor by anchor
+    Curve,
+    // Straight segments chained click after click
+    Polyline,
+    Shape(ShapeKind),
+    Select,
+    Move,
 }
 
 // Enum to represent supported file formats
@@ -92,28 +121,362 @@ enum AppState {
     Canvas(PaintApp),
 }
 
+// Kind of entry in the layer stack
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
+enum LayerKind {
+    #[default]
+    Raster,
+    // Groups hold no pixels, their children are the layers right below them with a greater depth
+    Group { collapsed: bool },
+    // Filter applied to everything composited below, holds no pixels either
+    Adjustment(Adjustment),
+}
+
+// Grayscale mask hiding parts of a layer without touching its pixels
+#[derive(Clone, PartialEq)]
+struct LayerMask {
+    // 255 shows the layer, 0 hides it
+    data: TileGrid<u8>,
+    enabled: bool,
+}
+
+impl LayerMask {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            data: TileGrid::new(width, height, 255),
+            enabled: true,
+        }
+    }
+}
+
+// Protections against accidental edits, a lock on a group applies to its children
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+struct LayerLocks {
+    // Only recolor existing pixels, keeping their alpha
+    alpha: bool,
+    pixels: bool,
+    position: bool,
+}
+
+impl LayerLocks {
+    const ALL: LayerLocks = LayerLocks { alpha: true, pixels: true, position: true };
+    
+    fn is_all(&self) -> bool {
+        self.pixels && self.position
+    }
+    
+    fn any(&self) -> bool {
+        self.alpha || self.pixels || self.position
+    }
+    
+    fn combine(&self, other: LayerLocks) -> LayerLocks {
+        LayerLocks {
+            alpha: self.alpha || other.alpha,
+            pixels: self.pixels || other.pixels,
+            position: self.position || other.position,
+        }
+    }
+}
+
 // Layer structure for storing each canvas layer (sans serde)
 #[derive(Clone, PartialEq)]
 struct Layer {
     name: String,
-    data: Vec<Option<Color32>>,
+    // Empty for groups and adjustment layers
+    data: PixelGrid,
     visible: bool,
+    opacity: f32,
+    blend_mode: BlendMode,
+    kind: LayerKind,
+    depth: usize,
+    mask: Option<LayerMask>,
+    locks: LayerLocks,
+    // Clipped to the layer below, an unclipped layer with nothing below it ignores the flag
+    clipped: bool,
+}
+
+impl Layer {
+    fn new_raster(name: String, data: PixelGrid, depth: usize) -> Self {
+        Self {
+            name,
+            data,
+            visible: true,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
+            kind: LayerKind::Raster,
+            depth,
+            mask: None,
+            locks: LayerLocks::default(),
+            clipped: false,
+        }
+    }
+    
+    fn new_group(name: String, depth: usize) -> Self {
+        Self {
+            kind: LayerKind::Group { collapsed: false },
+            ..Self::new_raster(name, PixelGrid::default(), depth)
+        }
+    }
+    
+    fn new_adjustment(name: String, adjustment: Adjustment, depth: usize) -> Self {
+        Self {
+            kind: LayerKind::Adjustment(adjustment),
+            ..Self::new_group(name, depth)
+        }
+    }
+    
+    #[inline]
+    fn is_group(&self) -> bool {
+        matches!(self.kind, LayerKind::Group { .. })
+    }
+    
+    #[inline]
+    fn has_pixels(&self) -> bool {
+        self.kind == LayerKind::Raster
+    }
+    
+    // Address and byte size of the tiles of the layer and of its mask
+    fn tile_addresses(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mask = self.mask.iter().flat_map(|mask| mask.data.tile_addresses());
+        self.data.tile_addresses().into_iter().chain(mask).map(|(_, address, size)| (address, size))
+    }
+}
+
+// Make the depths read from a file describe a valid stack: children come right before their
+// group one level deeper, and the last entry is at the top level
+fn normalize_depths(layers: &mut [Layer]) {
+    let mut limit = 0;
+    for layer in layers.iter_mut().rev() {
+        layer.depth = layer.depth.min(limit);
+        limit = layer.depth + usize::from(layer.is_group());
+    }
+}
+
+// Gray level used when painting a color on a mask
+fn mask_value(color: Option<compositing::Rgba>) -> u8 {
+    color.map_or(0, |c| {
+        ((0.299 * c[0] + 0.587 * c[1] + 0.114 * c[2]).clamp(0.0, 1.0) * 255.0).round() as u8
+    })
+}
+
+// Mask values are recorded in the history as gray colors
+#[inline]
+fn mask_color(value: u8) -> compositing::Rgba {
+    let v = value as f32 / 255.0;
+    [v, v, v, 1.0]
+}
+
+// Fully transparent composited pixels are stored as None
+#[inline]
+fn composited_pixel(color: compositing::Rgba) -> Option<compositing::Rgba> {
+    (color[3] > 0.0).then_some(color)
+}
+
+// Written tile of a layer or mask, `x` and `y` count tiles
+#[derive(Serialize, Deserialize)]
+struct TileData<T> {
+    x: usize,
+    y: usize,
+    data: Vec<T>,
+}
+
+// Serializable form of the stored tiles of a grid
+fn tiles_to_data<T: Copy + PartialEq, U>(grid: &TileGrid<T>, convert: impl Fn(T) -> U) -> Vec<TileData<U>> {
+    let mut coords: Vec<TileCoord> = grid.stored_tiles().collect();
+    coords.sort_by_key(|&(x, y)| (y, x));
+    coords.into_iter()
+        .filter_map(|coord| grid.tile(coord).map(|tile| TileData {
+            x: coord.0,
+            y: coord.1,
+            data: tile.iter().map(|&value| convert(value)).collect(),
+        }))
+        .collect()
+}
+
+// Rebuild a grid from saved tiles, or from the flat list written by older versions
+fn grid_from_data<T: Copy + PartialEq, U>(
+    width: usize,
+    height: usize,
+    default: T,
+    flat: Vec<U>,
+    tiles: Vec<TileData<U>>,
+    convert: impl Fn(U) -> T,
+) -> TileGrid<T> {
+    let mut grid = if flat.len() == width * height && !flat.is_empty() {
+        let pixels: Vec<T> = flat.into_iter().map(&convert).collect();
+        TileGrid::from_pixels(width, height, default, &pixels)
+    } else {
+        TileGrid::new(width, height, default)
+    };
+    for tile in tiles {
+        if tile.data.len() == TILE_AREA {
+            grid.insert_tile((tile.x, tile.y), tile.data.into_iter().map(&convert).collect());
+        }
+    }
+    grid
+}
+
+// Mask structure for serialization
+#[derive(Serialize, Deserialize)]
+struct MaskData {
+    // Flat values written by older versions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    data: Vec<u8>,
+    #[serde(default)]
+    tiles: Vec<TileData<u8>>,
+    enabled: bool,
 }
 
 // Layer structure for serialization
 #[derive(Serialize, Deserialize)]
 struct LayerData {
     name: String,
+    // Flat pixels written by older versions, only the written tiles are saved now
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     data: Vec<Option<[u8; 4]>>,
+    #[serde(default)]
+    tiles: Vec<TileData<Option<[u8; 4]>>>,
+    // Tiles of 16-bit and float documents, `tiles` is used for 8-bit ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tiles_16: Vec<TileData<Option<[u16; 4]>>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tiles_float: Vec<TileData<Option<[f32; 4]>>>,
+    visible: bool,
+    // Files saved before per-layer opacity existed load fully opaque
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+    #[serde(default)]
+    blend_mode: BlendMode,
+    #[serde(default)]
+    kind: LayerKind,
+    #[serde(default)]
+    depth: usize,
+    #[serde(default)]
+    mask: Option<MaskData>,
+    #[serde(default)]
+    locks: LayerLocks,
+    #[serde(default)]
+    clipped: bool,
+}
+
+fn default_opacity() -> f32 {
+    1.0
+}
+
+// Undo and redo steps saved in a .rustiq file. Every tile content is deflated and written
+// once in `tiles`, snapshot tiles the document still holds refer to its layers instead.
+#[derive(Serialize, Deserialize)]
+struct HistoryData {
+    // Base64 of the deflated raw tiles
+    tiles: Vec<String>,
+    // Steps of the undo tree in the order they were made
+    steps: Vec<HistoryStepData>,
+    // Step the saved document is at, none when everything is undone
+    current: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryStepData {
+    // Earlier step this one was made after, none for the start
+    parent: Option<usize>,
+    step: StepData,
+}
+
+#[derive(Serialize, Deserialize)]
+enum StepData {
+    Pixels {
+        action: HistoryAction,
+        changes: Vec<ChangeData>,
+    },
+    Layers {
+        action: HistoryAction,
+        width: usize,
+        height: usize,
+        active_layer_index: usize,
+        layers: Vec<SnapshotLayerData>,
+    },
+}
+
+// Tile changed by a tool, `before` and `after` index the saved tiles
+#[derive(Serialize, Deserialize)]
+struct ChangeData {
+    layer_index: usize,
+    target: EditTarget,
+    x: usize,
+    y: usize,
+    before: Option<usize>,
+    after: Option<usize>,
+}
+
+// Where the content of a snapshot tile is saved
+#[derive(Serialize, Deserialize)]
+enum TileSource {
+    // Same tile at the same position in this layer of the document, or in its mask
+    Layer(usize),
+    // Index in the saved tiles
+    Saved(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotTile {
+    x: usize,
+    y: usize,
+    source: TileSource,
+}
+
+// Layer of a saved snapshot, with the same settings as `LayerData`
+#[derive(Serialize, Deserialize)]
+struct SnapshotLayerData {
+    name: String,
     visible: bool,
+    opacity: f32,
+    blend_mode: BlendMode,
+    kind: LayerKind,
+    depth: usize,
+    locks: LayerLocks,
+    clipped: bool,
+    tiles: Vec<SnapshotTile>,
+    mask: Option<SnapshotMaskData>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotMaskData {
+    tiles: Vec<SnapshotTile>,
+    enabled: bool,
+}
+
+// Color saved in a .rustiq file of the given version
+fn color_from_file(rgba: [u8; 4], version: u32) -> Color32 {
+    let [r, g, b, a] = rgba;
+    if version == 0 {
+        Color32::from_rgba_premultiplied(r, g, b, a)
+    } else {
+        Color32::from_rgba_unmultiplied(r, g, b, a)
+    }
 }
 
 // Structure for saving and loading .rustiq files
 #[derive(Serialize, Deserialize)]
 struct RustiqueFile {
+    // Files written before versioning stored egui's premultiplied colors, later ones straight RGBA
+    #[serde(default)]
+    version: u32,
     width: usize,
     height: usize,
+    #[serde(default)]
+    bit_depth: BitDepth,
+    // ICC profile of the document, absent for sRGB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_profile: Option<Vec<u8>>,
+    // Pixels keep the values of the profile instead of sRGB
+    #[serde(default)]
+    profile_values: bool,
+    #[serde(default)]
+    linear_blending: bool,
     layers: Vec<LayerData>,
+    // Absent when the history was stripped or empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history: Option<HistoryData>,
     active_layer_index: usize,
     primary_color: [u8; 4],
     secondary_color: [u8; 4],
@@ -127,58 +490,327 @@ struct RustiqueFile {
 struct CanvasState {
     width: usize,
     height: usize,
+    // Precision of every raster layer of the document
+    bit_depth: BitDepth,
+    // Profile exports are converted to and tagged with, None for sRGB. Pixels are sRGB unless
+    // `profile_values` is set.
+    color_profile: Option<ColorProfile>,
+    // Image opened without conversion, the pixels are in `color_profile` and only converted for display
+    profile_values: bool,
+    // Blend brushes, layers and filters in linear light, the layers still store sRGB values
+    linear_blending: bool,
     layers: Vec<Layer>,
     active_layer_index: usize,
 }
 
 impl CanvasState {
-    fn new(width: usize, height: usize) -> Self {
-        let default_layer = Layer {
-            name: "Background".to_string(),
-            data: vec![None; width * height],
-            visible: true,
-        };
+    fn new(width: usize, height: usize, bit_depth: BitDepth) -> Self {
+        let default_layer = Layer::new_raster("Background".to_string(), PixelGrid::new(width, height, bit_depth), 0);
         
         Self {
             width,
             height,
+            bit_depth,
+            color_profile: None,
+            profile_values: false,
+            linear_blending: false,
             layers: vec![default_layer],
             active_layer_index: 0,
         }
     }
     
-    #[inline]
+    // Composite all visible layers from bottom to top
     fn get(&self, x: usize, y: usize) -> Option<Color32> {
-        if x < self.width && y < self.height {
-            // Iterate through layers from top to bottom
-            for layer_index in (0..self.layers.len()).rev() {
-                let layer = &self.layers[layer_index];
-                if layer.visible {
-                    let idx = y * self.width + x;
-                    if let Some(color) = layer.data[idx] {
-                        return Some(color);
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        
+        let p = (y % TILE_SIZE) * TILE_SIZE + x % TILE_SIZE;
+        let pixel = self.composite_range(0, self.layers.len(), 0, (x / TILE_SIZE, y / TILE_SIZE), p..p + 1);
+        let color = compositing::to_color32(self.out_of_blend_space(pixel[0]));
+        (color.a() > 0).then_some(color)
+    }
+    
+    // Stored sRGB color to the space colors are blended in
+    #[inline]
+    fn to_blend_space(&self, color: compositing::Rgba) -> compositing::Rgba {
+        if self.linear_blending { compositing::to_linear(color) } else { color }
+    }
+    
+    #[inline]
+    fn out_of_blend_space(&self, color: compositing::Rgba) -> compositing::Rgba {
+        if self.linear_blending { compositing::from_linear(color) } else { color }
+    }
+    
+    // Composite a color over a stored pixel, as the brush does
+    fn blend_colors(&self, dst: Option<compositing::Rgba>, src: compositing::Rgba) -> compositing::Rgba {
+        let blended = compositing::blend_colors(dst.map(|dst| self.to_blend_space(dst)), self.to_blend_space(src));
+        self.out_of_blend_space(blended)
+    }
+    
+    // Composite the `pixels` of one tile, given as indices in the tile, for the entries of
+    // `start..end` sitting at `depth`. Groups are composited in isolation and then blended as
+    // a single layer. The result is in the blending space of the document.
+    fn composite_range(&self, start: usize, end: usize, depth: usize, coord: TileCoord, pixels: Range<usize>) -> Vec<compositing::Rgba> {
+        let len = pixels.len();
+        let mut result = vec![compositing::TRANSPARENT; len];
+        // Alpha of the closest unclipped entry below, clipped layers only show where it is opaque
+        let mut clip_base: Option<Vec<f32>> = None;
+        let mut i = start;
+        while i < end {
+            // Children come before their group, skip ahead to the entry they belong to
+            let mut entry = i;
+            while entry < end && self.layers[entry].depth > depth {
+                entry += 1;
+            }
+            if entry >= end {
+                break;
+            }
+            
+            let layer = &self.layers[entry];
+            // An unclipped layer with nothing below it ignores the flag
+            let clipped = layer.clipped && clip_base.is_some();
+            let mut alpha = if clipped { Vec::new() } else { vec![0.0; len] };
+            
+            if layer.visible && layer.opacity > 0.0 {
+                let mask = layer.mask.as_ref()
+                    .filter(|mask| mask.enabled)
+                    .map(|mask| (mask.data.tile(coord), mask.data.default_value()));
+                let base = clip_base.as_ref().filter(|_| clipped);
+                // Opacity combined with the mask and the clipping base at a pixel
+                let coverage = |p: usize| {
+                    let mut coverage = layer.opacity;
+                    if let Some((tile, default)) = mask {
+                        coverage *= tile.map_or(default, |tile| tile[pixels.start + p]) as f32 / 255.0;
+                    }
+                    if let Some(base) = base {
+                        coverage *= base[p];
                     }
+                    coverage
+                };
+                
+                match &layer.kind {
+                    LayerKind::Raster => {
+                        if let Some(tile) = layer.data.tile_pixels(coord, pixels.clone()) {
+                            for (p, pixel) in tile.iter().enumerate() {
+                                let (Some(src), c) = (*pixel, coverage(p)) else { continue };
+                                if c > 0.0 {
+                                    let src = self.to_blend_space(src);
+                                    result[p] = compositing::blend_with_coverage(result[p], src, c, layer.blend_mode);
+                                    if let Some(a) = alpha.get_mut(p) {
+                                        *a = src[3] * c;
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    LayerKind::Group { .. } => {
+                        let inner = self.composite_range(i, entry, depth + 1, coord, pixels.clone());
+                        for (p, src) in inner.into_iter().enumerate() {
+                            let c = coverage(p);
+                            if src[3] > 0.0 && c > 0.0 {
+                                result[p] = compositing::blend_with_coverage(result[p], src, c, layer.blend_mode);
+                                if let Some(a) = alpha.get_mut(p) {
+                                    *a = src[3] * c;
+                                }
+                            }
+                        }
+                    },
+                    // Adjustments filter what is below them, faded by their opacity and mask
+                    LayerKind::Adjustment(adjustment) => {
+                        for (p, color) in result.iter_mut().enumerate() {
+                            let c = coverage(p);
+                            if c > 0.0 {
+                                *color = compositing::lerp(*color, adjustment.apply_premultiplied(*color), c);
+                                if let Some(a) = alpha.get_mut(p) {
+                                    *a = c;
+                                }
+                            }
+                        }
+                    },
                 }
             }
+            
+            if !clipped {
+                clip_base = Some(alpha);
+            }
+            i = entry + 1;
+        }
+        result
+    }
+    
+    // Composite `start..end` over the whole canvas, as rendering would. Tiles where none
+    // of the layers hold pixels stay empty.
+    fn flatten_range(&self, start: usize, end: usize, depth: usize) -> PixelGrid {
+        let mut grid = PixelGrid::new(self.width, self.height, self.bit_depth);
+        let tiles: Vec<(TileCoord, Vec<Option<compositing::Rgba>>)> = grid.tile_coords()
+            .into_par_iter()
+            .filter(|&coord| self.layers[start..end].iter().any(|layer| layer.data.has_tile(coord)))
+            .map(|coord| {
+                let pixels = self.composite_range(start, end, depth, coord, 0..TILE_AREA)
+                    .into_iter()
+                    .map(|color| composited_pixel(self.out_of_blend_space(color)))
+                    .collect();
+                (coord, pixels)
+            })
+            .collect();
+        for (coord, pixels) in tiles {
+            grid.insert_tile(coord, pixels);
+        }
+        grid
+    }
+    
+    // The image as displayed and exported
+    fn flatten(&self) -> PixelGrid {
+        self.flatten_range(0, self.layers.len(), 0)
+    }
+    
+    // First index of the block formed by a layer and, for groups, all of its children
+    fn subtree_start(&self, index: usize) -> usize {
+        let depth = self.layers[index].depth;
+        let mut start = index;
+        while start > 0 && self.layers[start - 1].depth > depth {
+            start -= 1;
+        }
+        start
+    }
+    
+    // Group containing the layer, if any
+    fn parent_of(&self, index: usize) -> Option<usize> {
+        let depth = self.layers[index].depth;
+        (index + 1..self.layers.len()).find(|&i| self.layers[i].depth < depth)
+    }
+    
+    // A layer is only shown when it and all of its groups are visible
+    fn is_shown(&self, index: usize) -> bool {
+        let mut current = Some(index);
+        while let Some(i) = current {
+            if !self.layers[i].visible {
+                return false;
+            }
+            current = self.parent_of(i);
+        }
+        true
+    }
+    
+    // Locks of a layer including the ones inherited from its groups
+    fn locks_of(&self, index: usize) -> LayerLocks {
+        let mut locks = LayerLocks::default();
+        let mut current = (index < self.layers.len()).then_some(index);
+        while let Some(i) = current {
+            locks = locks.combine(self.layers[i].locks);
+            current = self.parent_of(i);
+        }
+        locks
+    }
+    
+    // Whether a collapsed group hides the layer in the layers panel
+    fn is_folded(&self, index: usize) -> bool {
+        let mut current = self.parent_of(index);
+        while let Some(i) = current {
+            if self.layers[i].kind == (LayerKind::Group { collapsed: true }) {
+                return true;
+            }
+            current = self.parent_of(i);
+        }
+        false
+    }
+    
+    // Only visible layers can be painted on, groups only through their mask
+    fn can_paint_active_layer(&self, on_mask: bool) -> bool {
+        self.active_layer_index < self.layers.len()
+            && (on_mask || self.layers[self.active_layer_index].has_pixels())
+            && self.is_shown(self.active_layer_index)
+    }
+    
+    // Rearrange the layers, `order` lists the old index of each new position
+    fn reorder_layers(&mut self, order: &[usize]) {
+        let mut old_layers: Vec<Option<Layer>> = std::mem::take(&mut self.layers).into_iter().map(Some).collect();
+        self.layers = order.iter().filter_map(|&i| old_layers[i].take()).collect();
+        if let Some(position) = order.iter().position(|&i| i == self.active_layer_index) {
+            self.active_layer_index = position;
+        }
+    }
+    
+    #[inline]
+    fn get_from_active_layer(&self, x: usize, y: usize) -> Option<compositing::Rgba> {
+        if x < self.width && y < self.height && self.active_layer_index < self.layers.len() {
+            self.layers[self.active_layer_index].data.get(x, y)
+        } else {
+            None
         }
-        None
     }
     
+    // None when the active layer has no mask
     #[inline]
-    fn get_from_active_layer(&self, x: usize, y: usize) -> Option<Color32> {
+    fn get_mask_from_active_layer(&self, x: usize, y: usize) -> Option<u8> {
         if x < self.width && y < self.height && self.active_layer_index < self.layers.len() {
-            let idx = y * self.width + x;
-            self.layers[self.active_layer_index].data[idx]
+            self.layers[self.active_layer_index].mask.as_ref().map(|mask| mask.data.get(x, y))
         } else {
             None
         }
     }
     
     #[inline]
-    fn set(&mut self, x: usize, y: usize, color: Option<Color32>) {
+    fn set_mask(&mut self, x: usize, y: usize, value: u8) {
+        if let Some(mask) = self.layers.get_mut(self.active_layer_index).and_then(|layer| layer.mask.as_mut()) {
+            mask.data.set(x, y, value);
+        }
+    }
+    
+    // Pixels connected to (x, y) for which `matches` holds
+    fn flood_region<F: Fn(usize, usize) -> bool>(&self, x: usize, y: usize, matches: F) -> Vec<(usize, usize)> {
+        // Pre-allocate for better performance
+        let mut region = Vec::new();
+        let mut queue = VecDeque::with_capacity(1024);
+        let mut visited = vec![false; self.width * self.height];
+        queue.push_back((x, y));
+        
+        while let Some((cx, cy)) = queue.pop_front() {
+            let idx = cy * self.width + cx;
+            if visited[idx] || !matches(cx, cy) {
+                continue;
+            }
+            
+            visited[idx] = true;
+            region.push((cx, cy));
+            
+            // Add adjacent pixels to queue
+            if cx > 0 { queue.push_back((cx - 1, cy)); }
+            if cx + 1 < self.width { queue.push_back((cx + 1, cy)); }
+            if cy > 0 { queue.push_back((cx, cy - 1)); }
+            if cy + 1 < self.height { queue.push_back((cx, cy + 1)); }
+        }
+        region
+    }
+    
+    #[inline]
+    fn set(&mut self, x: usize, y: usize, color: Option<compositing::Rgba>) {
         if x < self.width && y < self.height && self.active_layer_index < self.layers.len() {
-            let idx = y * self.width + x;
-            self.layers[self.active_layer_index].data[idx] = color;
+            self.layers[self.active_layer_index].data.set(x, y, color);
+        }
+    }
+    
+    // Raw content of a tile of a layer or of its mask, see `PixelGrid::tile_bytes`
+    fn tile_bytes(&self, layer_index: usize, target: EditTarget, coord: TileCoord) -> Option<Vec<u8>> {
+        let layer = self.layers.get(layer_index)?;
+        match target {
+            EditTarget::Pixels => layer.data.tile_bytes(coord),
+            EditTarget::Mask => layer.mask.as_ref()?.data.tile(coord).map(|tile| tile.to_vec()),
+        }
+    }
+    
+    fn set_tile_bytes(&mut self, layer_index: usize, target: EditTarget, coord: TileCoord, bytes: Option<&[u8]>) {
+        let Some(layer) = self.layers.get_mut(layer_index) else {
+            return;
+        };
+        match (target, layer.mask.as_mut()) {
+            (EditTarget::Pixels, _) => layer.data.set_tile_bytes(coord, bytes),
+            (EditTarget::Mask, Some(mask)) => match bytes {
+                Some(bytes) => mask.data.insert_tile(coord, bytes.to_vec()),
+                None => mask.data.remove_tile(coord),
+            },
+            (EditTarget::Mask, None) => {},
         }
     }
     
@@ -188,14 +820,184 @@ impl CanvasState {
     }
 }
 
-// Store changes for efficient undo/redo
+// Part of a layer edited by the tools
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum EditTarget {
+    Pixels,
+    Mask,
+}
+
+// One tile changed by a tool, with its raw content before and after the change
+// deflated. None is a tile that holds no data.
 #[derive(Clone)]
 struct CanvasChange {
+    layer_index: usize,
+    target: EditTarget,
+    coord: TileCoord,
+    before: Option<Vec<u8>>,
+    after: Option<Vec<u8>>,
+}
+
+impl CanvasChange {
+    // Memory held by the change
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.before.as_ref().map_or(0, Vec::len) + self.after.as_ref().map_or(0, Vec::len)
+    }
+}
+
+// Layer stack saved by structural operations, with the canvas size for the ones changing it
+#[derive(Clone)]
+struct LayerSnapshot {
+    width: usize,
+    height: usize,
+    layers: Vec<Layer>,
+    active_layer_index: usize,
+}
+
+// Layer settings changed along a slider drag, pushed as one step when the drag ends
+struct LayerEdit {
+    before: LayerSnapshot,
+    changed: bool,
+}
+
+// Layers being dragged by the move tool, their content is moved from the saved state
+// so the preview never loses pixels pushed out of the canvas before the release
+struct MoveSession {
+    // Pushed as the undo step once the move is committed
+    before: LayerSnapshot,
+    layers: std::ops::Range<usize>,
+    offset: (i32, i32),
+}
+
+// Shape being dragged, painted with the secondary color when started with the right button
+struct ShapeDrag {
+    start: (i32, i32),
+    end: (i32, i32),
+    use_secondary: bool,
+}
+
+// Anchor of a curve or polyline, in canvas coordinates. The handles are the control points
+// of the curve on each side and sit on the anchor for a sharp corner.
+#[derive(Clone, Copy)]
+struct PathAnchor {
+    point: (f32, f32),
+    handle_in: (f32, f32),
+    handle_out: (f32, f32),
+}
+
+// Part of a path being dragged
+#[derive(Clone, Copy)]
+enum PathGrip {
+    Point(usize),
+    HandleIn(usize),
+    HandleOut(usize),
+}
+
+// Curve or polyline being placed, painted with the brush once finished
+struct PathDraft {
+    anchors: Vec<PathAnchor>,
+    use_secondary: bool,
+    dragging: Option<PathGrip>,
+}
+
+impl PathDraft {
+    // Points along the path, each curve split in short segments
+    fn points(&self) -> Vec<(f32, f32)> {
+        let mut points = Vec::new();
+        for (i, anchor) in self.anchors.iter().enumerate() {
+            match i.checked_sub(1).map(|previous| self.anchors[previous]) {
+                Some(previous) => points.extend(
+                    shapes::bezier_points(previous.point, previous.handle_out, anchor.handle_in, anchor.point).into_iter().skip(1)
+                ),
+                None => points.push(anchor.point),
+            }
+        }
+        points
+    }
+    
+    // Anchor or handle within `reach` of a position, the handles of corners are on their anchor
+    fn grip_at(&self, pos: (f32, f32), reach: f32) -> Option<PathGrip> {
+        let near = |point: (f32, f32)| (point.0 - pos.0).hypot(point.1 - pos.1) <= reach;
+        self.anchors.iter().enumerate().rev().find_map(|(i, anchor)| {
+            if anchor.handle_out != anchor.point && near(anchor.handle_out) {
+                Some(PathGrip::HandleOut(i))
+            } else if anchor.handle_in != anchor.point && near(anchor.handle_in) {
+                Some(PathGrip::HandleIn(i))
+            } else if near(anchor.point) {
+                Some(PathGrip::Point(i))
+            } else {
+                None
+            }
+        })
+    }
+    
+    // Move an anchor with its handles, or a handle with the opposite one mirrored when `mirror` is set
+    fn drag(&mut self, grip: PathGrip, pos: (f32, f32), mirror: bool) {
+        let mirrored = |point: (f32, f32)| (2.0 * point.0 - pos.0, 2.0 * point.1 - pos.1);
+        match grip {
+            PathGrip::Point(i) => {
+                let anchor = &mut self.anchors[i];
+                let (dx, dy) = (pos.0 - anchor.point.0, pos.1 - anchor.point.1);
+                anchor.point = pos;
+                anchor.handle_in = (anchor.handle_in.0 + dx, anchor.handle_in.1 + dy);
+                anchor.handle_out = (anchor.handle_out.0 + dx, anchor.handle_out.1 + dy);
+            },
+            PathGrip::HandleOut(i) => {
+                let anchor = &mut self.anchors[i];
+                anchor.handle_out = pos;
+                if mirror {
+                    anchor.handle_in = mirrored(anchor.point);
+                }
+            },
+            PathGrip::HandleIn(i) => {
+                let anchor = &mut self.anchors[i];
+                anchor.handle_in = pos;
+                if mirror {
+                    anchor.handle_out = mirrored(anchor.point);
+                }
+            },
+        }
+    }
+}
+
+// Rectangular selection in canvas pixels
+#[derive(Clone, Copy, PartialEq)]
+struct Selection {
     x: usize,
     y: usize,
-    layer_index: usize,
-    old_color: Option<Color32>,
-    new_color: Option<Color32>,
+    width: usize,
+    height: usize,
+}
+
+impl Selection {
+    // Rectangle spanning two corners, both included
+    fn from_corners(a: (usize, usize), b: (usize, usize)) -> Self {
+        Self {
+            x: a.0.min(b.0),
+            y: a.1.min(b.1),
+            width: a.0.abs_diff(b.0) + 1,
+            height: a.1.abs_diff(b.1) + 1,
+        }
+    }
+}
+
+// One entry of the undo/redo history
+enum UndoStep {
+    // Tiles of pixels and masks edited by the tools
+    Pixels(Vec<CanvasChange>),
+    // Layer stack to swap back in, holds the state before the change on the undo side
+    // and the state after it on the redo side
+    Layers(LayerSnapshot),
+}
+
+// Undo step with the action that made it, for the history panel
+struct HistoryEntry {
+    action: HistoryAction,
+    step: UndoStep,
+    // Memory only the history holds for the step, counted against the budget
+    size: usize,
+    // Tiles of a layer snapshot the document still held when it was measured, with their size
+    shared: Vec<(usize, usize)>,
 }
 
 // Dialog for asking to save before quitting
@@ -209,10 +1011,18 @@ enum SaveDialog {
 // Main struct for the paint application
 struct PaintApp {
     current_state: CanvasState,
-    undo_stack: Vec<Vec<CanvasChange>>,
-    redo_stack: Vec<Vec<CanvasChange>>,
-    current_changes: Vec<CanvasChange>,
+    undo_tree: HistoryTree<HistoryEntry>,
+    // Tiles touched by the stroke in progress with their raw content before it
+    stroke_tiles: HashMap<(usize, EditTarget, TileCoord), Option<Vec<u8>>>,
+    // Bytes the undo history may use
+    history_budget: usize,
+    // Tiles the layer snapshots share with the document, None when they must be measured again
+    history_shared: Option<HashSet<usize>>,
+    // Write the history in .rustiq files, turned off to deliver files without it
+    save_history: bool,
+    stroke_origin: HashMap<(usize, usize), Option<compositing::Rgba>>,
     current_tool: Tool,
+    edit_target: EditTarget,
     primary_color: Color32,
     secondary_color: Color32,
     saved_colors: Vec<Color32>,
@@ -229,6 +1039,15 @@ struct PaintApp {
     line_end: Option<(i32, i32)>,
     is_drawing_line: bool,
     is_first_click_line: bool,
+    shape_drag: Option<ShapeDrag>,
+    path_draft: Option<PathDraft>,
+    shape_style: ShapeStyle,
+    polygon_sides: usize,
+    corner_radius: i32,
+    selection: Option<Selection>,
+    selection_start: Option<(usize, usize)>,
+    move_session: Option<MoveSession>,
+    layer_edit: Option<LayerEdit>,
     has_unsaved_changes: bool,
     last_save_path: Option<String>,
     save_dialog: SaveDialog,
@@ -237,14 +1056,18 @@ struct PaintApp {
 
 impl PaintApp {
     // Initialize a new PaintApp
-    fn new(width: u32, height: u32, language: Language) -> Self {
-        let initial_state = CanvasState::new(width as usize, height as usize);
+    fn new(width: u32, height: u32, bit_depth: BitDepth, language: Language) -> Self {
+        let initial_state = CanvasState::new(width as usize, height as usize, bit_depth);
         Self {
             current_state: initial_state,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
-            current_changes: Vec::new(),
+            undo_tree: HistoryTree::default(),
+            stroke_tiles: HashMap::new(),
+            history_budget: DEFAULT_HISTORY_BUDGET,
+            history_shared: None,
+            save_history: true,
+            stroke_origin: HashMap::new(),
             current_tool: Tool::Brush,
+            edit_target: EditTarget::Pixels,
             primary_color: Color32::BLACK,
             secondary_color: Color32::WHITE,
             saved_colors: Vec::new(),
@@ -261,6 +1084,15 @@ impl PaintApp {
             line_end: None,
             is_drawing_line: false,
             is_first_click_line: true,
+            shape_drag: None,
+            path_draft: None,
+            shape_style: ShapeStyle::Outline,
+            polygon_sides: 5,
+            corner_radius: 16,
+            selection: None,
+            selection_start: None,
+            move_session: None,
+            layer_edit: None,
             has_unsaved_changes: false,
             last_save_path: None,
             save_dialog: SaveDialog::Hidden,
@@ -273,59 +1105,62 @@ impl PaintApp {
         let mut canvas = CanvasState {
             width: file.width,
             height: file.height,
+            bit_depth: file.bit_depth,
+            color_profile: file.color_profile.and_then(|data| ColorProfile::from_icc(data).ok()),
+            profile_values: file.profile_values,
+            linear_blending: file.linear_blending,
             layers: Vec::with_capacity(file.layers.len()),
             active_layer_index: file.active_layer_index,
         };
         
         // Convert the saved layers to Canvas layers
         for layer_data in file.layers {
-            let mut layer = Layer {
-                name: layer_data.name,
-                data: Vec::with_capacity(layer_data.data.len()),
-                visible: layer_data.visible,
+            let data = match file.bit_depth {
+                BitDepth::Eight if file.version == 0 => PixelGrid::Eight(grid_from_data(file.width, file.height, None, layer_data.data, layer_data.tiles, |pixel_opt| {
+                    pixel_opt.map(|rgba| color_from_file(rgba, 0).to_srgba_unmultiplied()).filter(|rgba| rgba[3] > 0)
+                })),
+                BitDepth::Eight => PixelGrid::Eight(grid_from_data(file.width, file.height, None, Vec::new(), layer_data.tiles, |pixel| pixel)),
+                BitDepth::Sixteen => PixelGrid::Sixteen(grid_from_data(file.width, file.height, None, Vec::new(), layer_data.tiles_16, |pixel| pixel)),
+                BitDepth::Float => PixelGrid::Float(grid_from_data(file.width, file.height, None, Vec::new(), layer_data.tiles_float, |pixel| pixel)),
             };
+            let mask = layer_data.mask.map(|mask| LayerMask {
+                data: grid_from_data(file.width, file.height, 255, mask.data, mask.tiles, |value| value),
+                enabled: mask.enabled,
+            });
             
-            for pixel_opt in layer_data.data {
-                if let Some(rgba) = pixel_opt {
-                    layer.data.push(Some(Color32::from_rgba_unmultiplied(rgba[0], rgba[1], rgba[2], rgba[3])));
-                } else {
-                    layer.data.push(None);
-                }
-            }
-            
-            canvas.layers.push(layer);
+            canvas.layers.push(Layer {
+                name: layer_data.name,
+                data,
+                visible: layer_data.visible,
+                opacity: layer_data.opacity.clamp(0.0, 1.0),
+                blend_mode: layer_data.blend_mode,
+                kind: layer_data.kind,
+                depth: layer_data.depth,
+                mask,
+                locks: layer_data.locks,
+                clipped: layer_data.clipped,
+            });
         }
+        normalize_depths(&mut canvas.layers);
         
-        let primary_color = Color32::from_rgba_unmultiplied(
-            file.primary_color[0],
-            file.primary_color[1],
-            file.primary_color[2],
-            file.primary_color[3]
-        );
-        
-        let secondary_color = Color32::from_rgba_unmultiplied(
-            file.secondary_color[0],
-            file.secondary_color[1],
-            file.secondary_color[2],
-            file.secondary_color[3]
-        );
+        let primary_color = color_from_file(file.primary_color, file.version);
+        let secondary_color = color_from_file(file.secondary_color, file.version);
         
         let mut saved_colors = Vec::with_capacity(file.saved_colors.len());
         for color_data in file.saved_colors {
-            saved_colors.push(Color32::from_rgba_unmultiplied(
-                color_data[0],
-                color_data[1],
-                color_data[2],
-                color_data[3]
-            ));
+            saved_colors.push(color_from_file(color_data, file.version));
         }
         
-        Self {
+        let mut app = Self {
             current_state: canvas,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
-            current_changes: Vec::new(),
+            undo_tree: HistoryTree::default(),
+            stroke_tiles: HashMap::new(),
+            history_budget: DEFAULT_HISTORY_BUDGET,
+            history_shared: None,
+            save_history: true,
+            stroke_origin: HashMap::new(),
             current_tool: Tool::Brush,
+            edit_target: EditTarget::Pixels,
             primary_color,
             secondary_color,
             saved_colors,
@@ -342,11 +1177,24 @@ impl PaintApp {
             line_end: None,
             is_drawing_line: false,
             is_first_click_line: true,
+            shape_drag: None,
+            path_draft: None,
+            shape_style: ShapeStyle::Outline,
+            polygon_sides: 5,
+            corner_radius: 16,
+            selection: None,
+            selection_start: None,
+            move_session: None,
+            layer_edit: None,
             has_unsaved_changes: false,
             last_save_path: None,
             save_dialog: SaveDialog::Hidden,
             language,
+        };
+        if let Some(history) = file.history {
+            app.restore_history(history);
         }
+        app
     }
 
     // New method to detect file format from path
@@ -380,19 +1228,41 @@ impl PaintApp {
     
     // Save as image with any supported format
     fn save_as_image(&mut self, path: &str, format: ImageFormat) -> Result<(), String> {
-        let width = self.current_state.width;
-        let height = self.current_state.height;
-        let mut img = ImageBuffer::new(width as u32, height as u32);
+        let width = self.current_state.width as u32;
+        let height = self.current_state.height as u32;
+        
+        // Files store straight alpha, transparent pixels are written as transparent black
+        let mut pixels = self.current_state.flatten().to_straight();
         
-        // Process rows one by one
-        for y in 0..height {
-            for x in 0..width {
-                let color = self.current_state.get(x, y).unwrap_or(Color32::TRANSPARENT);
-                img.put_pixel(x as u32, y as u32, Rgba([color.r(), color.g(), color.b(), color.a()]));
+        // PNG, JPEG and TIFF are converted to the document profile and carry it, other formats stay sRGB
+        let profile = self.current_state.color_profile.clone().unwrap_or_else(ColorProfile::srgb);
+        let tagged = matches!(format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Tiff);
+        if tagged != self.current_state.profile_values {
+            if tagged {
+                profile.convert_from_srgb(&mut pixels);
+            } else {
+                profile.convert_to_srgb(&mut pixels);
             }
         }
+        let to_u8 = |pixels: &[[f32; 4]]| -> Vec<u8> {
+            pixels.iter().flatten().map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8).collect()
+        };
+        // PNG and TIFF keep the precision of high bit depth documents, other formats only take 8 bits
+        let samples = if self.current_state.bit_depth == BitDepth::Eight {
+            Samples::Eight(to_u8(&pixels))
+        } else {
+            Samples::Sixteen(pixels.iter().flatten().map(|v| (v.clamp(0.0, 1.0) * 65535.0).round() as u16).collect())
+        };
+        
+        let result = match format {
+            ImageFormat::Png => color_profile::write_png(Path::new(path), width, height, samples, &profile),
+            ImageFormat::Tiff => color_profile::write_tiff(Path::new(path), width, height, samples, &profile),
+            ImageFormat::Jpeg => color_profile::write_jpeg(Path::new(path), width, height, &to_u8(&pixels), &profile),
+            _ => image::save_buffer_with_format(path, &to_u8(&pixels), width, height, image::ColorType::Rgba8, format)
+                .map_err(|e| e.to_string()),
+        };
 
-        match img.save_with_format(path, format) {
+        match result {
             Ok(_) => {
                 self.has_unsaved_changes = false;
                 self.last_save_path = Some(path.to_string());
@@ -403,7 +1273,9 @@ impl PaintApp {
     }
     
     // Open any supported file
-    fn open_file(path: &str, language: Language) -> Result<Self, String> {
+    // `convert_profile` turns tagged 8 and 16-bit images to sRGB, otherwise they keep their values
+    // and are converted for display only. Float images are always converted since nothing is lost.
+    fn open_file(path: &str, language: Language, convert_profile: bool) -> Result<Self, String> {
         let format = Self::detect_format(path);
         
         match format {
@@ -432,25 +1304,40 @@ impl PaintApp {
                     Ok(img) => {
                         let width = img.width() as usize;
                         let height = img.height() as usize;
-                        let mut canvas = CanvasState::new(width, height);
                         
-                        let rgba_img = img.to_rgba8();
-                        for y in 0..height {
-                            for x in 0..width {
-                                let pixel = rgba_img.get_pixel(x as u32, y as u32);
-                                if pixel[3] > 0 { // Not fully transparent
-                                    let color = Color32::from_rgba_unmultiplied(pixel[0], pixel[1], pixel[2], pixel[3]);
-                                    canvas.set(x, y, Some(color));
-                                }
-                            }
+                        // 16-bit and float images open as documents of the same precision
+                        let bit_depth = match img.color() {
+                            image::ColorType::L16 | image::ColorType::La16 | image::ColorType::Rgb16 | image::ColorType::Rgba16 => BitDepth::Sixteen,
+                            image::ColorType::Rgb32F | image::ColorType::Rgba32F => BitDepth::Float,
+                            _ => BitDepth::Eight,
+                        };
+                        // Channels are kept straight, so they come back unchanged on export
+                        let mut pixels: Vec<[f32; 4]> = img.into_rgba32f().pixels().map(|pixel| pixel.0).collect();
+                        
+                        // Tagged images keep their profile for export
+                        let profile = format.get_image_format()
+                            .and_then(|image_format| color_profile::read_embedded(Path::new(path), image_format))
+                            .and_then(|data| ColorProfile::from_icc(data).ok());
+                        let convert = convert_profile || bit_depth == BitDepth::Float;
+                        if let Some(profile) = &profile && convert {
+                            profile.convert_to_srgb(&mut pixels);
                         }
                         
+                        let mut canvas = CanvasState::new(width, height, bit_depth);
+                        canvas.layers[0].data = PixelGrid::from_straight(width, height, bit_depth, &pixels);
+                        canvas.profile_values = profile.is_some() && !convert;
+                        canvas.color_profile = profile;
+                        
                         let mut app = Self {
                             current_state: canvas,
-                            undo_stack: Vec::new(),
-                            redo_stack: Vec::new(),
-                            current_changes: Vec::new(),
+                            undo_tree: HistoryTree::default(),
+                            stroke_tiles: HashMap::new(),
+                            history_budget: DEFAULT_HISTORY_BUDGET,
+                            history_shared: None,
+                            save_history: true,
+                            stroke_origin: HashMap::new(),
                             current_tool: Tool::Brush,
+                            edit_target: EditTarget::Pixels,
                             primary_color: Color32::BLACK,
                             secondary_color: Color32::WHITE,
                             saved_colors: Vec::new(),
@@ -467,6 +1354,15 @@ impl PaintApp {
                             line_end: None,
                             is_drawing_line: false,
                             is_first_click_line: true,
+                            shape_drag: None,
+                            path_draft: None,
+                            shape_style: ShapeStyle::Outline,
+                            polygon_sides: 5,
+                            corner_radius: 16,
+                            selection: None,
+                            selection_start: None,
+                            move_session: None,
+                            layer_edit: None,
                             has_unsaved_changes: false,
                             last_save_path: Some(path.to_string()),
                             save_dialog: SaveDialog::Hidden,
@@ -483,7 +1379,7 @@ impl PaintApp {
     
     // Create a PaintApp from a PNG file (deprecated but kept for backward compatibility)
     fn from_png_file(path: &str, language: Language) -> Option<Self> {
-        match Self::open_file(path, language) {
+        match Self::open_file(path, language, true) {
             Ok(app) => Some(app),
             Err(_) => None
         }
@@ -491,41 +1387,57 @@ impl PaintApp {
 
     // Save the current image as a .rustiq file
     fn save_as_rustiq(&mut self, path: &str) -> Result<(), String> {
+        self.save_state();
         let mut layers = Vec::with_capacity(self.current_state.layers.len());
         
         for layer in &self.current_state.layers {
-            let mut layer_data = Vec::with_capacity(layer.data.len());
-            
-            for &pixel_opt in &layer.data {
-                match pixel_opt {
-                    Some(color) => {
-                        layer_data.push(Some([color.r(), color.g(), color.b(), color.a()]));
-                    },
-                    None => {
-                        layer_data.push(None);
-                    }
-                }
+            let (mut tiles, mut tiles_16, mut tiles_float) = (Vec::new(), Vec::new(), Vec::new());
+            match &layer.data {
+                PixelGrid::Eight(grid) => tiles = tiles_to_data(grid, |pixel| pixel),
+                PixelGrid::Sixteen(grid) => tiles_16 = tiles_to_data(grid, |pixel| pixel),
+                PixelGrid::Float(grid) => tiles_float = tiles_to_data(grid, |pixel| pixel),
             }
+            let mask = layer.mask.as_ref().map(|mask| MaskData {
+                data: Vec::new(),
+                tiles: tiles_to_data(&mask.data, |value| value),
+                enabled: mask.enabled,
+            });
             
             layers.push(LayerData {
                 name: layer.name.clone(),
-                data: layer_data,
+                data: Vec::new(),
+                tiles,
+                tiles_16,
+                tiles_float,
                 visible: layer.visible,
+                opacity: layer.opacity,
+                blend_mode: layer.blend_mode,
+                kind: layer.kind.clone(),
+                depth: layer.depth,
+                mask,
+                locks: layer.locks,
+                clipped: layer.clipped,
             });
         }
         
         let mut saved_colors = Vec::with_capacity(self.saved_colors.len());
         for &color in &self.saved_colors {
-            saved_colors.push([color.r(), color.g(), color.b(), color.a()]);
+            saved_colors.push(color.to_srgba_unmultiplied());
         }
         
         let rustiq_file = RustiqueFile {
+            version: RUSTIQ_VERSION,
             width: self.current_state.width,
             height: self.current_state.height,
+            bit_depth: self.current_state.bit_depth,
+            color_profile: self.current_state.color_profile.as_ref().map(|profile| profile.data().to_vec()),
+            profile_values: self.current_state.profile_values,
+            linear_blending: self.current_state.linear_blending,
             layers,
+            history: (self.save_history && !self.undo_tree.is_empty()).then(|| self.history_data()),
             active_layer_index: self.current_state.active_layer_index,
-            primary_color: [self.primary_color.r(), self.primary_color.g(), self.primary_color.b(), self.primary_color.a()],
-            secondary_color: [self.secondary_color.r(), self.secondary_color.g(), self.secondary_color.b(), self.secondary_color.a()],
+            primary_color: self.primary_color.to_srgba_unmultiplied(),
+            secondary_color: self.secondary_color.to_srgba_unmultiplied(),
             saved_colors,
             brush_size: self.brush_size,
             eraser_size: self.eraser_size,
@@ -575,60 +1487,860 @@ impl PaintApp {
         }
     }
 
+    // History section of a .rustiq file
+    fn history_data(&self) -> HistoryData {
+        // Tiles the document holds, by address
+        let mut live = HashMap::new();
+        for (index, layer) in self.current_state.layers.iter().enumerate() {
+            let mask = layer.mask.iter().flat_map(|mask| mask.data.tile_addresses());
+            for (coord, address, _) in layer.data.tile_addresses().into_iter().chain(mask) {
+                live.insert(address, (index, coord));
+            }
+        }
+        let mut tiles = Vec::new();
+        // Snapshot tiles already saved, by address
+        let mut saved = HashMap::new();
+        
+        let mut step_data = |entry: &HistoryEntry| match &entry.step {
+            UndoStep::Pixels(changes) => {
+                let mut save = |packed: &Option<Vec<u8>>| packed.as_ref().map(|packed| {
+                    tiles.push(history::to_base64(packed));
+                    tiles.len() - 1
+                });
+                let changes = changes.iter()
+                    .map(|change| ChangeData {
+                        layer_index: change.layer_index,
+                        target: change.target,
+                        x: change.coord.0,
+                        y: change.coord.1,
+                        before: save(&change.before),
+                        after: save(&change.after),
+                    })
+                    .collect();
+                StepData::Pixels { action: entry.action, changes }
+            },
+            UndoStep::Layers(snapshot) => {
+                let mut save = |(coord, address, _): (TileCoord, usize, usize), bytes: Option<Vec<u8>>| {
+                    let source = match live.get(&address) {
+                        Some(&(index, live_coord)) if live_coord == coord => TileSource::Layer(index),
+                        _ => TileSource::Saved(*saved.entry(address).or_insert_with(|| {
+                            tiles.push(history::to_base64(&history::pack(&bytes.unwrap_or_default())));
+                            tiles.len() - 1
+                        })),
+                    };
+                    SnapshotTile { x: coord.0, y: coord.1, source }
+                };
+                let layers = snapshot.layers.iter()
+                    .map(|layer| SnapshotLayerData {
+                        name: layer.name.clone(),
+                        visible: layer.visible,
+                        opacity: layer.opacity,
+                        blend_mode: layer.blend_mode,
+                        kind: layer.kind.clone(),
+                        depth: layer.depth,
+                        locks: layer.locks,
+                        clipped: layer.clipped,
+                        tiles: layer.data.tile_addresses().into_iter()
+                            .map(|tile| save(tile, layer.data.tile_bytes(tile.0)))
+                            .collect(),
+                        mask: layer.mask.as_ref().map(|mask| SnapshotMaskData {
+                            tiles: mask.data.tile_addresses()
+                                .map(|tile| save(tile, mask.data.tile(tile.0).map(|values| values.to_vec())))
+                                .collect(),
+                            enabled: mask.enabled,
+                        }),
+                    })
+                    .collect();
+                StepData::Layers {
+                    action: entry.action,
+                    width: snapshot.width,
+                    height: snapshot.height,
+                    active_layer_index: snapshot.active_layer_index,
+                    layers,
+                }
+            },
+        };
+        
+        let steps = (0..self.undo_tree.len())
+            .map(|node| HistoryStepData {
+                parent: self.undo_tree.parent(node),
+                step: step_data(self.undo_tree.get(node)),
+            })
+            .collect();
+        HistoryData { tiles, steps, current: self.undo_tree.current() }
+    }
+    
+    // Rebuild the undo and redo stacks saved in a .rustiq file. Saved snapshot tiles are
+    // decoded once into a strip of tiles and shared from there, like they were before saving.
+    fn restore_history(&mut self, history: HistoryData) {
+        let packed: Vec<Option<Vec<u8>>> = history.tiles.iter().map(|text| history::from_base64(text)).collect();
+        let packed_tile = |index: Option<usize>| index.and_then(|index| packed.get(index).cloned().flatten());
+        let depth = self.current_state.bit_depth;
+        let mut pixel_strip = PixelGrid::new(packed.len() * TILE_SIZE, TILE_SIZE, depth);
+        let mut mask_strip = TileGrid::new(packed.len() * TILE_SIZE, TILE_SIZE, 255);
+        
+        let mut restore = |step: StepData, state: &CanvasState| match step {
+            StepData::Pixels { action, changes } => {
+                let changes = changes.into_iter()
+                    .map(|change| CanvasChange {
+                        layer_index: change.layer_index,
+                        target: change.target,
+                        coord: (change.x, change.y),
+                        before: packed_tile(change.before),
+                        after: packed_tile(change.after),
+                    })
+                    .collect();
+                (action, UndoStep::Pixels(changes))
+            },
+            StepData::Layers { action, width, height, active_layer_index, layers } => {
+                let mut layers = layers.into_iter()
+                    .map(|layer_data| {
+                        let live = |source: &TileSource| match *source {
+                            TileSource::Layer(index) => state.layers.get(index),
+                            TileSource::Saved(_) => None,
+                        };
+                        let mut data = if layer_data.kind == LayerKind::Raster {
+                            PixelGrid::new(width, height, depth)
+                        } else {
+                            PixelGrid::default()
+                        };
+                        for tile in &layer_data.tiles {
+                            let coord = (tile.x, tile.y);
+                            match tile.source {
+                                TileSource::Layer(_) => if let Some(layer) = live(&tile.source) {
+                                    data.share_tile(coord, &layer.data, coord);
+                                },
+                                TileSource::Saved(index) => {
+                                    if !pixel_strip.has_tile((index, 0)) {
+                                        let bytes = packed_tile(Some(index)).and_then(|packed| history::unpack(&packed));
+                                        pixel_strip.set_tile_bytes((index, 0), bytes.as_deref());
+                                    }
+                                    data.share_tile(coord, &pixel_strip, (index, 0));
+                                },
+                            }
+                        }
+                        let mask = layer_data.mask.map(|mask_data| {
+                            let mut mask = LayerMask { enabled: mask_data.enabled, ..LayerMask::new(width, height) };
+                            for tile in &mask_data.tiles {
+                                let coord = (tile.x, tile.y);
+                                match tile.source {
+                                    TileSource::Layer(_) => if let Some(live_mask) = live(&tile.source).and_then(|layer| layer.mask.as_ref()) {
+                                        mask.data.share_tile(coord, &live_mask.data, coord);
+                                    },
+                                    TileSource::Saved(index) => {
+                                        if !mask_strip.has_tile((index, 0)) {
+                                            let values = packed_tile(Some(index)).and_then(|packed| history::unpack(&packed));
+                                            mask_strip.insert_tile((index, 0), values.unwrap_or_default());
+                                        }
+                                        mask.data.share_tile(coord, &mask_strip, (index, 0));
+                                    },
+                                }
+                            }
+                            mask
+                        });
+                        Layer {
+                            name: layer_data.name,
+                            data,
+                            visible: layer_data.visible,
+                            opacity: layer_data.opacity.clamp(0.0, 1.0),
+                            blend_mode: layer_data.blend_mode,
+                            kind: layer_data.kind,
+                            depth: layer_data.depth,
+                            mask,
+                            locks: layer_data.locks,
+                            clipped: layer_data.clipped,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                normalize_depths(&mut layers);
+                (action, UndoStep::Layers(LayerSnapshot { width, height, layers, active_layer_index }))
+            },
+        };
+        
+        // Parents come before their steps, anything else can't be rebuilt
+        let count = history.steps.len();
+        let live = self.live_tiles();
+        for (node, step_data) in history.steps.into_iter().enumerate() {
+            let (action, step) = restore(step_data.step, &self.current_state);
+            let size = Self::step_size(&step, &live);
+            self.undo_tree.insert(step_data.parent.filter(|&parent| parent < node), HistoryEntry { action, step, size, shared: Vec::new() });
+        }
+        self.undo_tree.move_to(history.current.filter(|&current| current < count));
+        self.history_shared = None;
+        self.trim_history(&live);
+    }
+    
     // Layer management functions
+    // New layers are created right above the active one, inside the same group
     fn add_layer(&mut self, name: String) {
-        self.current_state.layers.push(Layer {
-            name,
-            data: vec![None; self.current_state.width * self.current_state.height],
-            visible: true,
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let (index, depth) = match state.layers.get(state.active_layer_index) {
+            Some(active) => (state.active_layer_index + 1, active.depth),
+            None => (state.layers.len(), 0),
+        };
+        state.layers.insert(index, Layer::new_raster(name, PixelGrid::new(state.width, state.height, state.bit_depth), depth));
+        state.active_layer_index = index;
+        self.push_undo_step(HistoryAction::AddLayer, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Put a layer (or group) into a new group
+    fn group_layer(&mut self, index: usize, name: String) {
+        if index >= self.current_state.layers.len() {
+            return;
+        }
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let start = state.subtree_start(index);
+        let depth = state.layers[index].depth;
+        for layer in &mut state.layers[start..=index] {
+            layer.depth += 1;
+        }
+        state.layers.insert(index + 1, Layer::new_group(name, depth));
+        if state.active_layer_index > index {
+            state.active_layer_index += 1;
+        }
+        self.push_undo_step(HistoryAction::GroupLayer, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Removing a group also removes its children
+    fn remove_layer(&mut self, index: usize) {
+        let state = &mut self.current_state;
+        if index >= state.layers.len() {
+            return;
+        }
+        let start = state.subtree_start(index);
+        let count = index - start + 1;
+        if count >= state.layers.len() {
+            return;
+        }
+        
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        state.layers.drain(start..=index);
+        if state.active_layer_index > index {
+            state.active_layer_index -= count;
+        } else if state.active_layer_index >= start {
+            state.active_layer_index = start.min(state.layers.len() - 1);
+        }
+        self.push_undo_step(HistoryAction::RemoveLayer, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Move a layer (with its children for groups) one step towards the top of the stack.
+    // It leaves its group at the top and enters expanded groups it meets.
+    fn move_layer_up(&mut self, index: usize) {
+        let len = self.current_state.layers.len();
+        if index + 1 >= len {
+            return;
+        }
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let start = state.subtree_start(index);
+        let depth = state.layers[index].depth;
+        let above = index + 1;
+        let above_depth = state.layers[above].depth;
+        
+        if above_depth < depth {
+            // Top of its group: move above the group
+            let order: Vec<usize> = (0..start).chain([above]).chain(start..=index).chain(above + 1..len).collect();
+            state.reorder_layers(&order);
+            for layer in &mut state.layers[start + 1..=index + 1] {
+                layer.depth -= 1;
+            }
+        } else {
+            // Find the entry above and the block it forms with its children
+            let mut entry = above;
+            while entry + 1 < len && state.layers[entry].depth > depth {
+                entry += 1;
+            }
+            if state.layers[entry].kind == (LayerKind::Group { collapsed: false }) {
+                // Children sit right below their group, so entering it only changes the depth
+                for layer in &mut state.layers[start..=index] {
+                    layer.depth += 1;
+                }
+            } else {
+                let order: Vec<usize> = (0..start).chain(above..=entry).chain(start..=index).chain(entry + 1..len).collect();
+                state.reorder_layers(&order);
+            }
+        }
+        self.push_undo_step(HistoryAction::ReorderLayers, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Move a layer (with its children for groups) one step towards the bottom of the stack.
+    // It leaves its group at the bottom and enters expanded groups it meets.
+    fn move_layer_down(&mut self, index: usize) {
+        let len = self.current_state.layers.len();
+        if index >= len {
+            return;
+        }
+        let start = self.current_state.subtree_start(index);
+        let depth = self.current_state.layers[index].depth;
+        // Already at the bottom of the stack
+        if start == 0 && depth == 0 {
+            return;
+        }
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        
+        if start == 0 || state.layers[start - 1].depth < depth {
+            // Bottom of its group: dropping one level puts it right below the group
+            for layer in &mut state.layers[start..=index] {
+                layer.depth -= 1;
+            }
+        } else {
+            let below = start - 1;
+            if state.layers[below].kind == (LayerKind::Group { collapsed: false }) {
+                // Enter the group as its top child
+                let order: Vec<usize> = (0..below).chain(start..=index).chain([below]).chain(index + 1..len).collect();
+                state.reorder_layers(&order);
+                for layer in &mut state.layers[below..index] {
+                    layer.depth += 1;
+                }
+            } else {
+                let below_start = state.subtree_start(below);
+                let order: Vec<usize> = (0..below_start).chain(start..=index).chain(below_start..=below).chain(index + 1..len).collect();
+                state.reorder_layers(&order);
+            }
+        }
+        self.push_undo_step(HistoryAction::ReorderLayers, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Mask management functions
+    fn add_layer_mask(&mut self, index: usize) {
+        if self.current_state.layers.get(index).is_none_or(|layer| layer.mask.is_some()) {
+            return;
+        }
+        let before = self.snapshot_layers();
+        let (width, height) = (self.current_state.width, self.current_state.height);
+        self.current_state.layers[index].mask = Some(LayerMask::new(width, height));
+        self.push_undo_step(HistoryAction::AddMask, UndoStep::Layers(before));
+        self.edit_target = EditTarget::Mask;
+        self.texture_dirty = true;
+    }
+    
+    fn toggle_layer_mask(&mut self, index: usize) {
+        if self.current_state.layers.get(index).is_none_or(|layer| layer.mask.is_none()) {
+            return;
+        }
+        let before = self.snapshot_layers();
+        if let Some(mask) = self.current_state.layers[index].mask.as_mut() {
+            mask.enabled = !mask.enabled;
+        }
+        self.push_undo_step(HistoryAction::ToggleMask, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Bake the mask into the layer alpha, groups have no pixels so their mask is just dropped
+    fn apply_layer_mask(&mut self, index: usize) {
+        if self.current_state.locks_of(index).pixels {
+            return;
+        }
+        if self.current_state.layers.get(index).is_none_or(|layer| layer.mask.is_none()) {
+            return;
+        }
+        let before = self.snapshot_layers();
+        let layer = &mut self.current_state.layers[index];
+        if let Some(mask) = layer.mask.take() {
+            layer.data.apply_mask(&mask.data);
+        }
+        self.push_undo_step(HistoryAction::ApplyMask, UndoStep::Layers(before));
+        self.edit_target = EditTarget::Pixels;
+        self.texture_dirty = true;
+    }
+    
+    fn delete_layer_mask(&mut self, index: usize) {
+        if self.current_state.layers.get(index).is_none_or(|layer| layer.mask.is_none()) {
+            return;
+        }
+        let before = self.snapshot_layers();
+        self.current_state.layers[index].mask = None;
+        self.push_undo_step(HistoryAction::DeleteMask, UndoStep::Layers(before));
+        self.edit_target = EditTarget::Pixels;
+        self.texture_dirty = true;
+    }
+    
+    fn toggle_layer_clipping(&mut self, index: usize) {
+        if index < self.current_state.layers.len() {
+            let before = self.snapshot_layers();
+            let layer = &mut self.current_state.layers[index];
+            layer.clipped = !layer.clipped;
+            self.push_undo_step(HistoryAction::Clipping, UndoStep::Layers(before));
+            self.texture_dirty = true;
+        }
+    }
+    
+    fn set_layer_locks(&mut self, index: usize, locks: LayerLocks) {
+        if index < self.current_state.layers.len() {
+            let before = self.snapshot_layers();
+            self.current_state.layers[index].locks = locks;
+            self.push_undo_step(HistoryAction::LayerLocks, UndoStep::Layers(before));
+        }
+    }
+    
+    fn toggle_group_collapsed(&mut self, index: usize) {
+        if matches!(self.current_state.layers.get(index).map(|layer| &layer.kind), Some(LayerKind::Group { .. })) {
+            let before = self.snapshot_layers();
+            if let LayerKind::Group { collapsed } = &mut self.current_state.layers[index].kind {
+                *collapsed = !*collapsed;
+            }
+            self.push_undo_step(HistoryAction::CollapseGroup, UndoStep::Layers(before));
+        }
+    }
+    
+    // Copy a layer (or a group with its children) right above it
+    fn duplicate_layer(&mut self, index: usize, copy_suffix: &str) {
+        if index >= self.current_state.layers.len() {
+            return;
+        }
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let start = state.subtree_start(index);
+        let mut copy: Vec<Layer> = state.layers[start..=index].to_vec();
+        if let Some(top) = copy.last_mut() {
+            top.name = format!("{} {}", top.name, copy_suffix);
+        }
+        let count = copy.len();
+        state.layers.splice(index + 1..index + 1, copy);
+        state.active_layer_index = index + count;
+        self.push_undo_step(HistoryAction::DuplicateLayer, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Adjustment layers are added right above the active layer, like new layers
+    fn add_adjustment_layer(&mut self, name: String, adjustment: Adjustment) {
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let (index, depth) = match state.layers.get(state.active_layer_index) {
+            Some(active) => (state.active_layer_index + 1, active.depth),
+            None => (state.layers.len(), 0),
+        };
+        state.layers.insert(index, Layer::new_adjustment(name, adjustment, depth));
+        state.active_layer_index = index;
+        self.push_undo_step(HistoryAction::AddAdjustment, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    fn set_layer_adjustment(&mut self, index: usize, adjustment: Adjustment) {
+        if matches!(self.current_state.layers.get(index).map(|layer| &layer.kind), Some(LayerKind::Adjustment(_))) {
+            let before = self.settings_snapshot();
+            self.current_state.layers[index].kind = LayerKind::Adjustment(adjustment);
+            if let Some(before) = before {
+                self.push_undo_step(HistoryAction::AdjustLayer, UndoStep::Layers(before));
+            }
+            self.texture_dirty = true;
+            self.has_unsaved_changes = true;
+        }
+    }
+    
+    // Merge a layer (or group) into the raster layer right below it in the same group
+    fn merge_down(&mut self, index: usize) {
+        let state = &self.current_state;
+        if index >= state.layers.len() {
+            return;
+        }
+        let start = state.subtree_start(index);
+        let depth = state.layers[index].depth;
+        if start == 0 {
+            return;
+        }
+        let below = start - 1;
+        let below_layer = &state.layers[below];
+        if below_layer.depth != depth || !below_layer.has_pixels() {
+            return;
+        }
+        // Hidden or locked layers are left alone
+        if !state.layers[index].visible || !below_layer.visible
+            || state.locks_of(index).pixels || state.locks_of(below).pixels {
+            return;
+        }
+        
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let data = state.flatten_range(below, index + 1, depth);
+        let merged = &mut state.layers[below];
+        merged.data = data;
+        merged.opacity = 1.0;
+        merged.blend_mode = BlendMode::Normal;
+        merged.mask = None;
+        state.layers.drain(start..=index);
+        state.active_layer_index = below;
+        self.push_undo_step(HistoryAction::MergeDown, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Merge every visible layer into one, hidden layers are kept as they are
+    fn merge_visible(&mut self, name: String) {
+        let state = &self.current_state;
+        let len = state.layers.len();
+        let visible_roots: Vec<usize> = (0..len)
+            .filter(|&i| state.layers[i].depth == 0 && state.layers[i].visible)
+            .collect();
+        if visible_roots.is_empty() {
+            return;
+        }
+        // Nothing is merged while a visible layer is locked
+        if visible_roots.iter().any(|&root| (state.subtree_start(root)..=root).any(|i| state.locks_of(i).pixels)) {
+            return;
+        }
+        
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let data = state.flatten_range(0, len, 0);
+        let insert_at = state.subtree_start(visible_roots[0]);
+        
+        // Keep the blocks of hidden top-level entries
+        let mut layers = Vec::with_capacity(len);
+        let mut merged_index = None;
+        let mut i = 0;
+        while i < len {
+            let mut entry = i;
+            while entry + 1 < len && state.layers[entry].depth > 0 {
+                entry += 1;
+            }
+            if state.layers[entry].visible {
+                if i == insert_at {
+                    merged_index = Some(layers.len());
+                    layers.push(Layer::new_raster(name.clone(), PixelGrid::default(), 0));
+                }
+            } else {
+                layers.extend(state.layers[i..=entry].iter().cloned());
+            }
+            i = entry + 1;
+        }
+        
+        let merged_index = merged_index.unwrap_or(0);
+        layers[merged_index].data = data;
+        state.layers = layers;
+        state.active_layer_index = merged_index;
+        self.push_undo_step(HistoryAction::MergeVisible, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Replace the whole stack by a single layer, hidden layers are discarded
+    fn flatten_image(&mut self, name: String) {
+        // Locked layers, hidden ones included, keep the stack as it is
+        let state = &self.current_state;
+        if (0..state.layers.len()).any(|i| state.locks_of(i).pixels) {
+            return;
+        }
+        
+        let before = self.snapshot_layers();
+        let state = &mut self.current_state;
+        let data = state.flatten_range(0, state.layers.len(), 0);
+        state.layers = vec![Layer::new_raster(name, data, 0)];
+        state.active_layer_index = 0;
+        self.push_undo_step(HistoryAction::Flatten, UndoStep::Layers(before));
+        self.texture_dirty = true;
+    }
+    
+    // Canvas size operations
+    // Make the canvas the `width` x `height` rectangle starting at (x, y) of the current one,
+    // parts outside the current canvas are padded with transparency
+    fn crop_canvas(&mut self, x: isize, y: isize, width: usize, height: usize, action: HistoryAction) {
+        let state = &self.current_state;
+        if width == 0 || height == 0 || (x == 0 && y == 0 && width == state.width && height == state.height) {
+            return;
+        }
+        
+        let moved = |px: usize, py: usize| {
+            let (nx, ny) = (px as isize - x, py as isize - y);
+            (nx >= 0 && ny >= 0).then_some((nx as usize, ny as usize))
+        };
+        self.transform_layers(
+            action,
+            0..self.current_state.layers.len(),
+            (width, height),
+            |data| data.remap(width, height, moved),
+            |mask| mask.remap(width, height, moved),
+        );
+    }
+    
+    // Change the canvas size, `anchor` places the current content (0 left/top, 1 center, 2 right/bottom)
+    fn resize_canvas(&mut self, width: usize, height: usize, anchor: (usize, usize)) {
+        let x = (self.current_state.width as isize - width as isize) * anchor.0.min(2) as isize / 2;
+        let y = (self.current_state.height as isize - height as isize) * anchor.1.min(2) as isize / 2;
+        self.crop_canvas(x, y, width, height, HistoryAction::CanvasSize);
+    }
+    
+    fn crop_to_selection(&mut self) {
+        if let Some(selection) = self.selection {
+            self.crop_canvas(selection.x as isize, selection.y as isize, selection.width, selection.height, HistoryAction::Crop);
+        }
+    }
+    
+    // Crop the fully transparent borders, every raster layer is taken into account
+    fn trim_canvas(&mut self) {
+        let bounds = self.current_state.layers.iter()
+            .filter_map(|layer| layer.data.bounds())
+            .reduce(|a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)));
+        if let Some((x0, y0, x1, y1)) = bounds {
+            self.crop_canvas(x0 as isize, y0 as isize, x1 - x0, y1 - y0, HistoryAction::Trim);
+        }
+    }
+    
+    // Scale the whole document, every layer and mask is resampled with the same filter
+    fn scale_image(&mut self, width: usize, height: usize, filter: ResampleFilter) {
+        let state = &self.current_state;
+        if width == 0 || height == 0 || (width == state.width && height == state.height) {
+            return;
+        }
+        
+        self.transform_layers(
+            HistoryAction::Scale,
+            0..self.current_state.layers.len(),
+            (width, height),
+            |data| transform::resample_pixels(data, width, height, filter),
+            |mask| transform::resample_mask(mask, width, height, filter),
+        );
+    }
+    
+    // Flip or turn the whole document
+    fn orient_image(&mut self, orientation: Orientation) {
+        let (width, height) = (self.current_state.width, self.current_state.height);
+        let (new_width, new_height) = orientation.output_size(width, height);
+        let moved = |x: usize, y: usize| Some(orientation.map(x, y, width, height));
+        self.transform_layers(
+            HistoryAction::Orient(orientation),
+            0..self.current_state.layers.len(),
+            (new_width, new_height),
+            |data| data.remap(new_width, new_height, moved),
+            |mask| mask.remap(new_width, new_height, moved),
+        );
+    }
+    
+    // Rotate the whole document by an arbitrary angle, the canvas grows to fit it
+    fn rotate_image(&mut self, angle: f32, filter: ResampleFilter) {
+        let (width, height) = transform::rotated_size(self.current_state.width, self.current_state.height, angle);
+        self.transform_layers(
+            HistoryAction::Rotate,
+            0..self.current_state.layers.len(),
+            (width, height),
+            |data| transform::rotate_pixels(data, width, height, angle, filter),
+            |mask| transform::rotate_mask(mask, width, height, angle, filter),
+        );
+    }
+    
+    // Layers of the block formed by a layer and its children, None when a lock forbids moving them
+    fn transformable_layers(&self, index: usize) -> Option<std::ops::Range<usize>> {
+        let state = &self.current_state;
+        if index >= state.layers.len() {
+            return None;
+        }
+        let start = state.subtree_start(index);
+        let locked = (start..=index).any(|i| {
+            let locks = state.locks_of(i);
+            locks.position || locks.pixels
+        });
+        (!locked).then_some(start..index + 1)
+    }
+    
+    // Flip or turn a layer (every layer for groups) inside the canvas, around its center
+    fn orient_layer(&mut self, index: usize, orientation: Orientation) {
+        let Some(layers) = self.transformable_layers(index) else {
+            return;
+        };
+        let (width, height) = (self.current_state.width, self.current_state.height);
+        let (turned_width, turned_height) = orientation.output_size(width, height);
+        let offset_x = (width as isize - turned_width as isize) / 2;
+        let offset_y = (height as isize - turned_height as isize) / 2;
+        let moved = |x: usize, y: usize| {
+            let (tx, ty) = orientation.map(x, y, width, height);
+            let (nx, ny) = (tx as isize + offset_x, ty as isize + offset_y);
+            (nx >= 0 && ny >= 0).then_some((nx as usize, ny as usize))
+        };
+        self.transform_layers(
+            HistoryAction::Orient(orientation),
+            layers,
+            (width, height),
+            |data| data.remap(width, height, moved),
+            |mask| mask.remap(width, height, moved),
+        );
+    }
+    
+    // Rotate a layer (every layer for groups) by an arbitrary angle around the canvas center
+    fn rotate_layer(&mut self, index: usize, angle: f32, filter: ResampleFilter) {
+        let Some(layers) = self.transformable_layers(index) else {
+            return;
+        };
+        let (width, height) = (self.current_state.width, self.current_state.height);
+        self.transform_layers(
+            HistoryAction::Rotate,
+            layers,
+            (width, height),
+            |data| transform::rotate_pixels(data, width, height, angle, filter),
+            |mask| transform::rotate_mask(mask, width, height, angle, filter),
+        );
+    }
+    
+    // Start moving the active layer (with its children for groups), false when it is locked
+    fn begin_move(&mut self) -> bool {
+        if self.move_session.is_some() {
+            return true;
+        }
+        let Some(layers) = self.transformable_layers(self.current_state.active_layer_index) else {
+            return false;
+        };
+        let before = self.snapshot_layers();
+        self.move_session = Some(MoveSession { before, layers, offset: (0, 0) });
+        true
+    }
+    
+    // Live preview of the moved layers, the offset is relative to the start of the move
+    fn set_move_offset(&mut self, offset: (i32, i32)) {
+        let Some(session) = self.move_session.as_mut().filter(|session| session.offset != offset) else {
+            return;
+        };
+        session.offset = offset;
+        let (width, height) = (self.current_state.width, self.current_state.height);
+        let moved = |x: usize, y: usize| {
+            let (nx, ny) = (x as i64 + offset.0 as i64, y as i64 + offset.1 as i64);
+            (nx >= 0 && ny >= 0).then_some((nx as usize, ny as usize))
+        };
+        for i in session.layers.clone() {
+            let (Some(layer), Some(original)) = (self.current_state.layers.get_mut(i), session.before.layers.get(i)) else {
+                continue;
+            };
+            if layer.has_pixels() {
+                layer.data = original.data.remap(width, height, moved);
+            }
+            if let (Some(mask), Some(original_mask)) = (layer.mask.as_mut(), original.mask.as_ref()) {
+                mask.data = original_mask.data.remap(width, height, moved);
+            }
+        }
+        self.texture_dirty = true;
+    }
+    
+    // Record the whole move as a single undo step
+    fn commit_move(&mut self) {
+        if let Some(session) = self.move_session.take().filter(|session| session.offset != (0, 0)) {
+            self.push_undo_step(HistoryAction::MoveLayer, UndoStep::Layers(session.before));
+        }
+    }
+    
+    // Put the layers back where they were before the move
+    fn cancel_move(&mut self) {
+        if let Some(session) = self.move_session.take() {
+            self.swap_layers(session.before);
+            self.texture_dirty = true;
+        }
+    }
+    
+    // Arrow keys move the active layer, each nudge is its own undo step
+    fn nudge_active_layer(&mut self, dx: i32, dy: i32) {
+        if self.move_session.is_none() && self.begin_move() {
+            self.set_move_offset((dx, dy));
+            self.commit_move();
+        }
+    }
+    
+    // Replace the pixels and masks of some layers in one undo step, the canvas takes the given size
+    fn transform_layers<P, M>(&mut self, action: HistoryAction, layers: std::ops::Range<usize>, size: (usize, usize), pixels: P, mask: M)
+    where
+        P: Fn(&PixelGrid) -> PixelGrid + Sync,
+        M: Fn(&TileGrid<u8>) -> TileGrid<u8> + Sync,
+    {
+        let before = self.snapshot_layers();
+        self.current_state.layers[layers].par_iter_mut().for_each(|layer| {
+            if layer.has_pixels() {
+                layer.data = pixels(&layer.data);
+            }
+            if let Some(layer_mask) = layer.mask.as_mut() {
+                layer_mask.data = mask(&layer_mask.data);
+            }
         });
-        self.current_state.active_layer_index = self.current_state.layers.len() - 1;
+        if size != (self.current_state.width, self.current_state.height) {
+            self.current_state.width = size.0;
+            self.current_state.height = size.1;
+            self.selection = None;
+        }
+        self.push_undo_step(action, UndoStep::Layers(before));
         self.texture_dirty = true;
+    }
+    
+    // Profile of the exports, None goes back to sRGB
+    fn set_color_profile(&mut self, profile: Option<ColorProfile>) {
+        // Without a profile the kept values are read as sRGB
+        self.current_state.profile_values &= profile.is_some();
+        self.current_state.color_profile = profile;
         self.has_unsaved_changes = true;
+        self.texture_dirty = true;
     }
     
-    fn remove_layer(&mut self, index: usize) {
-        if self.current_state.layers.len() > 1 && index < self.current_state.layers.len() {
-            self.current_state.layers.remove(index);
-            if self.current_state.active_layer_index >= self.current_state.layers.len() {
-                self.current_state.active_layer_index = self.current_state.layers.len() - 1;
-            }
-            self.texture_dirty = true;
+    fn set_linear_blending(&mut self, linear: bool) {
+        if self.current_state.linear_blending != linear {
+            self.current_state.linear_blending = linear;
             self.has_unsaved_changes = true;
+            self.texture_dirty = true;
         }
     }
     
-    fn move_layer_up(&mut self, index: usize) {
-        if index > 0 && index < self.current_state.layers.len() {
-            self.current_state.layers.swap(index, index - 1);
-            if self.current_state.active_layer_index == index {
-                self.current_state.active_layer_index -= 1;
-            } else if self.current_state.active_layer_index == index - 1 {
-                self.current_state.active_layer_index += 1;
-            }
+    // Use an .icc/.icm file as the document profile, only profiles pixels can be converted to are accepted
+    fn load_color_profile(&mut self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", get_text("error_reading_file", self.language), e))?;
+        let profile = ColorProfile::from_icc(data)
+            .map_err(|e| format!("{}: {}", get_text("unsupported_color_profile", self.language), e))?;
+        if !profile.can_convert() {
+            return Err(get_text("unsupported_color_profile", self.language));
+        }
+        self.set_color_profile(Some(profile));
+        Ok(())
+    }
+    
+    fn toggle_layer_visibility(&mut self, index: usize) {
+        if index < self.current_state.layers.len() {
+            let before = self.snapshot_layers();
+            self.current_state.layers[index].visible = !self.current_state.layers[index].visible;
+            self.push_undo_step(HistoryAction::LayerVisibility, UndoStep::Layers(before));
             self.texture_dirty = true;
-            self.has_unsaved_changes = true;
         }
     }
     
-    fn move_layer_down(&mut self, index: usize) {
-        if index < self.current_state.layers.len() - 1 {
-            self.current_state.layers.swap(index, index + 1);
-            if self.current_state.active_layer_index == index {
-                self.current_state.active_layer_index += 1;
-            } else if self.current_state.active_layer_index == index + 1 {
-                self.current_state.active_layer_index -= 1;
+    // Start collecting the layer settings changed by a slider drag into one step
+    fn begin_layer_edit(&mut self) {
+        if self.layer_edit.is_none() {
+            let before = self.snapshot_layers();
+            self.layer_edit = Some(LayerEdit { before, changed: false });
+        }
+    }
+    
+    fn end_layer_edit(&mut self, action: HistoryAction) {
+        if let Some(edit) = self.layer_edit.take() && edit.changed {
+            self.push_undo_step(action, UndoStep::Layers(edit.before));
+        }
+    }
+    
+    // Layer stack before a settings change, none while a drag collects the changes
+    fn settings_snapshot(&mut self) -> Option<LayerSnapshot> {
+        if let Some(edit) = &mut self.layer_edit {
+            edit.changed = true;
+            return None;
+        }
+        Some(self.snapshot_layers())
+    }
+    
+    fn set_layer_opacity(&mut self, index: usize, opacity: f32) {
+        if index < self.current_state.layers.len() {
+            let before = self.settings_snapshot();
+            self.current_state.layers[index].opacity = opacity.clamp(0.0, 1.0);
+            if let Some(before) = before {
+                self.push_undo_step(HistoryAction::LayerOpacity, UndoStep::Layers(before));
             }
             self.texture_dirty = true;
             self.has_unsaved_changes = true;
         }
     }
     
-    fn toggle_layer_visibility(&mut self, index: usize) {
+    fn set_layer_blend_mode(&mut self, index: usize, blend_mode: BlendMode) {
         if index < self.current_state.layers.len() {
-            self.current_state.layers[index].visible = !self.current_state.layers[index].visible;
+            let before = self.snapshot_layers();
+            self.current_state.layers[index].blend_mode = blend_mode;
+            self.push_undo_step(HistoryAction::BlendMode, UndoStep::Layers(before));
             self.texture_dirty = true;
-            self.has_unsaved_changes = true;
         }
     }
     
@@ -639,9 +2351,10 @@ impl PaintApp {
     }
     
     fn rename_layer(&mut self, index: usize, name: String) {
-        if index < self.current_state.layers.len() {
+        if self.current_state.layers.get(index).is_some_and(|layer| layer.name != name) {
+            let before = self.snapshot_layers();
             self.current_state.layers[index].name = name;
-            self.has_unsaved_changes = true;
+            self.push_undo_step(HistoryAction::RenameLayer, UndoStep::Layers(before));
         }
     }
     
@@ -674,93 +2387,301 @@ impl PaintApp {
         }
     }
 
-    // Record a pixel change for undo/redo
-    fn record_change(&mut self, x: usize, y: usize, new_color: Option<Color32>) {
-        if x < self.current_state.width && y < self.current_state.height {
+    // Record a pixel change for undo/redo, `locks` are the ones of the active layer
+    fn record_change(&mut self, x: usize, y: usize, new_color: Option<compositing::Rgba>, locks: LayerLocks) {
+        if x < self.current_state.width && y < self.current_state.height && !locks.pixels {
             let old_color = self.current_state.get_from_active_layer(x, y);
+            
+            // With a locked alpha only existing pixels are recolored
+            let new_color = if locks.alpha {
+                match (old_color, new_color) {
+                    (Some(old), Some(new)) if new[3] > 0.0 => Some(compositing::scale(new, old[3] / new[3])),
+                    _ => return,
+                }
+            } else {
+                new_color
+            };
+            // Compare with the value the layer would actually store
+            let new_color = self.current_state.bit_depth.quantize(new_color);
+            
             if old_color != new_color {
-                self.current_changes.push(CanvasChange {
-                    x, 
-                    y, 
-                    layer_index: self.current_state.active_layer_index,
-                    old_color, 
-                    new_color
-                });
+                self.remember_tile(EditTarget::Pixels, x, y);
                 self.current_state.set(x, y, new_color);
                 self.has_unsaved_changes = true;
             }
         }
     }
+    
+    // Record a mask change for undo/redo, callers check the pixel lock
+    fn record_mask_change(&mut self, x: usize, y: usize, value: u8) {
+        let old_value = self.current_state.get_mask_from_active_layer(x, y);
+        if old_value.is_some_and(|old_value| old_value != value) {
+            self.remember_tile(EditTarget::Mask, x, y);
+            self.current_state.set_mask(x, y, value);
+            self.has_unsaved_changes = true;
+        }
+    }
+    
+    // Keep the content of a tile of the active layer the first time the stroke changes it
+    fn remember_tile(&mut self, target: EditTarget, x: usize, y: usize) {
+        let layer_index = self.current_state.active_layer_index;
+        let coord = (x / TILE_SIZE, y / TILE_SIZE);
+        if !self.stroke_tiles.contains_key(&(layer_index, target, coord)) {
+            let before = self.current_state.tile_bytes(layer_index, target, coord);
+            self.stroke_tiles.insert((layer_index, target, coord), before);
+        }
+    }
+    
+    // Tools paint on the mask when it is selected and the active layer has one
+    fn editing_mask(&self) -> bool {
+        self.edit_target == EditTarget::Mask
+            && self.current_state.layers.get(self.current_state.active_layer_index).is_some_and(|layer| layer.mask.is_some())
+    }
 
     // Save the current state for undo functionality
     fn save_state(&mut self) {
-        if !self.current_changes.is_empty() {
-            self.undo_stack.push(std::mem::take(&mut self.current_changes));
-            self.current_changes = Vec::new();
-            if self.undo_stack.len() > MAX_UNDO_STEPS {
-                self.undo_stack.remove(0);
+        self.stroke_origin.clear();
+        if !self.stroke_tiles.is_empty() {
+            let touched: Vec<_> = std::mem::take(&mut self.stroke_tiles).into_iter().collect();
+            // Free the pixel tiles that erasing left empty
+            for &((layer_index, target, coord), _) in &touched {
+                if let (EditTarget::Pixels, Some(layer)) = (target, self.current_state.layers.get_mut(layer_index)) {
+                    layer.data.compact_tile(coord);
+                }
+            }
+            let state = &self.current_state;
+            let changes: Vec<CanvasChange> = touched.into_par_iter()
+                .filter_map(|((layer_index, target, coord), before)| {
+                    let after = state.tile_bytes(layer_index, target, coord);
+                    (before != after).then(|| CanvasChange {
+                        layer_index,
+                        target,
+                        coord,
+                        before: before.as_deref().map(history::pack),
+                        after: after.as_deref().map(history::pack),
+                    })
+                })
+                .collect();
+            if !changes.is_empty() {
+                self.push_undo_step(self.stroke_action(), UndoStep::Pixels(changes));
             }
-            self.redo_stack.clear();
             self.is_drawing = false;
-            self.has_unsaved_changes = true;
         }
     }
+    
+    // History name of the pixel changes made with the current tool
+    fn stroke_action(&self) -> HistoryAction {
+        match self.current_tool {
+            Tool::Eraser => HistoryAction::Erase,
+            Tool::PaintBucket => HistoryAction::Fill,
+            Tool::Line => HistoryAction::Line,
+            Tool::Shape(kind) => HistoryAction::Shape(kind),
+            Tool::Curve => HistoryAction::Curve,
+            Tool::Polyline => HistoryAction::Polyline,
+            _ => HistoryAction::BrushStroke,
+        }
+    }
+    
+    fn push_undo_step(&mut self, action: HistoryAction, step: UndoStep) {
+        // A new snapshot can hold tiles the older ones share, all of them are measured again
+        if matches!(step, UndoStep::Layers(_)) {
+            self.history_shared = None;
+        }
+        let live = self.history_live_tiles();
+        let size = Self::step_size(&step, &live);
+        // Undone steps stay in the tree as another branch
+        self.undo_tree.push(HistoryEntry { action, step, size, shared: Vec::new() });
+        self.trim_history(&live);
+        self.has_unsaved_changes = true;
+    }
+    
+    // Memory only the history holds for a step, `live` being the tiles of the document
+    fn step_size(step: &UndoStep, live: &HashSet<usize>) -> usize {
+        match step {
+            UndoStep::Pixels(changes) => changes.iter().map(CanvasChange::size).sum(),
+            UndoStep::Layers(snapshot) => Self::snapshot_size(snapshot, live, &mut HashSet::new()).0,
+        }
+    }
+    
+    // Tiles of the document, only gathered when layer snapshots may share some of them
+    fn history_live_tiles(&self) -> HashSet<usize> {
+        match &self.history_shared {
+            Some(shared) if shared.is_empty() => HashSet::new(),
+            _ => self.live_tiles(),
+        }
+    }
+    
+    // Addresses of the tiles the document holds
+    fn live_tiles(&self) -> HashSet<usize> {
+        self.current_state.layers.iter()
+            .flat_map(Layer::tile_addresses)
+            .map(|(address, _)| address)
+            .collect()
+    }
+    
+    // Layer snapshots share the tiles the document still uses, so only the tiles it no longer
+    // has are counted, and those in `counted` already were for another snapshot. The shared
+    // tiles are returned with their size.
+    fn snapshot_size(snapshot: &LayerSnapshot, live: &HashSet<usize>, counted: &mut HashSet<usize>) -> (usize, Vec<(usize, usize)>) {
+        let mut shared = Vec::new();
+        let mut tiles = 0;
+        for (address, size) in snapshot.layers.iter().flat_map(Layer::tile_addresses) {
+            if live.contains(&address) {
+                shared.push((address, size));
+            } else if counted.insert(address) {
+                tiles += size;
+            }
+        }
+        (std::mem::size_of::<LayerSnapshot>() + snapshot.layers.len() * std::mem::size_of::<Layer>() + tiles, shared)
+    }
+    
+    // Keep the sizes of the layer snapshots up to date, the tiles they shared with the document
+    // become theirs alone once it paints over them. Only those tiles are looked at again unless
+    // the snapshots changed.
+    fn refresh_history_sizes(&mut self, live: &HashSet<usize>) {
+        if let Some(shared) = &mut self.history_shared {
+            if shared.iter().all(|address| live.contains(address)) {
+                return;
+            }
+            shared.retain(|address| live.contains(address));
+            let mut counted = HashSet::new();
+            for node in 0..self.undo_tree.len() {
+                let entry = self.undo_tree.get_mut(node);
+                let mut released = 0;
+                entry.shared.retain(|&(address, size)| {
+                    let kept = live.contains(&address);
+                    if !kept && counted.insert(address) {
+                        released += size;
+                    }
+                    kept
+                });
+                entry.size += released;
+            }
+            return;
+        }
+        
+        let mut counted = HashSet::new();
+        let mut shared = HashSet::new();
+        for node in 0..self.undo_tree.len() {
+            let entry = self.undo_tree.get_mut(node);
+            if let UndoStep::Layers(snapshot) = &entry.step {
+                let (size, tiles) = Self::snapshot_size(snapshot, live, &mut counted);
+                shared.extend(tiles.iter().map(|&(address, _)| address));
+                entry.size = size;
+                entry.shared = tiles;
+            }
+        }
+        self.history_shared = Some(shared);
+    }
+    
+    fn history_size(&self) -> usize {
+        self.undo_tree.iter().map(|entry| entry.size).sum()
+    }
+    
+    // Drop the oldest steps until the history fits its budget, the current step is always kept
+    fn trim_history(&mut self, live: &HashSet<usize>) {
+        loop {
+            self.refresh_history_sizes(live);
+            let snapshots = self.snapshot_count();
+            self.undo_tree.trim(self.history_budget, |entry| entry.size);
+            // A dropped snapshot may have been the one counting tiles the others still hold
+            if self.snapshot_count() == snapshots {
+                break;
+            }
+            self.history_shared = None;
+        }
+    }
+    
+    fn snapshot_count(&self) -> usize {
+        self.undo_tree.iter().filter(|entry| matches!(entry.step, UndoStep::Layers(_))).count()
+    }
+    
+    fn set_history_budget(&mut self, budget: usize) {
+        self.history_budget = budget;
+        let live = self.history_live_tiles();
+        self.trim_history(&live);
+    }
+    
+    // Take a copy of the layer stack before a structural operation
+    fn snapshot_layers(&mut self) -> LayerSnapshot {
+        // Pending pixel changes must stay before the structural step in the history
+        self.save_state();
+        LayerSnapshot {
+            width: self.current_state.width,
+            height: self.current_state.height,
+            layers: self.current_state.layers.clone(),
+            active_layer_index: self.current_state.active_layer_index,
+        }
+    }
+    
+    // Put a saved layer stack back and return the one it replaces
+    fn swap_layers(&mut self, snapshot: LayerSnapshot) -> LayerSnapshot {
+        let state = &mut self.current_state;
+        let previous = LayerSnapshot {
+            width: std::mem::replace(&mut state.width, snapshot.width),
+            height: std::mem::replace(&mut state.height, snapshot.height),
+            layers: std::mem::replace(&mut state.layers, snapshot.layers),
+            active_layer_index: state.active_layer_index,
+        };
+        state.active_layer_index = snapshot.active_layer_index;
+        // The selection may not fit a restored canvas size
+        if previous.width != state.width || previous.height != state.height {
+            self.selection = None;
+        }
+        previous
+    }
 
     // Undo the last action
     fn undo(&mut self) {
-        if let Some(changes) = self.undo_stack.pop() {
-            let mut redo_changes = Vec::with_capacity(changes.len());
-            
-            // Apply changes in reverse
-            for change in changes.iter().rev() {
-                let layer_index_backup = self.current_state.active_layer_index;
-                self.current_state.active_layer_index = change.layer_index;
-                
-                // Store the original change for redo
-                redo_changes.push(CanvasChange {
-                    x: change.x,
-                    y: change.y,
-                    layer_index: change.layer_index,
-                    old_color: change.old_color,  // Keep the original old color
-                    new_color: change.new_color,  // Keep the original new color
-                });
-                
-                self.current_state.set(change.x, change.y, change.old_color);
-                self.current_state.active_layer_index = layer_index_backup;
-            }
-            
-            self.redo_stack.push(redo_changes);
-            self.texture_dirty = true;
-            self.has_unsaved_changes = true;
+        if let Some(node) = self.undo_tree.step_back() {
+            self.apply_step(node, false);
         }
     }
 
-    // Redo the last undone action
+    // Redo the next action of the current branch
     fn redo(&mut self) {
-        if let Some(changes) = self.redo_stack.pop() {
-            let mut undo_changes = Vec::with_capacity(changes.len());
-            
-            // Apply changes in reverse
-            for change in changes.iter().rev() {
-                let layer_index_backup = self.current_state.active_layer_index;
-                self.current_state.active_layer_index = change.layer_index;
-                
-                let current_color = self.current_state.get_from_active_layer(change.x, change.y);
-                undo_changes.push(CanvasChange {
-                    x: change.x,
-                    y: change.y,
-                    layer_index: change.layer_index,
-                    old_color: current_color,
-                    new_color: change.new_color,
-                });
-                
-                self.current_state.set(change.x, change.y, change.new_color);
-                self.current_state.active_layer_index = layer_index_backup;
-            }
-            
-            self.undo_stack.push(undo_changes);
-            self.texture_dirty = true;
-            self.has_unsaved_changes = true;
+        if let Some(node) = self.undo_tree.step_forward() {
+            self.apply_step(node, true);
+        }
+    }
+    
+    // Replay a step of the undo tree forwards (redo) or backwards (undo)
+    fn apply_step(&mut self, node: usize, forward: bool) {
+        let step = std::mem::replace(&mut self.undo_tree.get_mut(node).step, UndoStep::Pixels(Vec::new()));
+        let step = match step {
+            UndoStep::Pixels(changes) => {
+                for change in &changes {
+                    let packed = if forward { &change.after } else { &change.before };
+                    let bytes = packed.as_deref().and_then(history::unpack);
+                    self.current_state.set_tile_bytes(change.layer_index, change.target, change.coord, bytes.as_deref());
+                }
+                UndoStep::Pixels(changes)
+            },
+            // Snapshots hold the other side of the change once swapped, the next trim
+            // measures all of them again
+            UndoStep::Layers(snapshot) => {
+                self.history_shared = None;
+                UndoStep::Layers(self.swap_layers(snapshot))
+            },
+        };
+        self.texture_dirty = true;
+        self.has_unsaved_changes = true;
+        let live = if matches!(step, UndoStep::Layers(_)) { self.live_tiles() } else { HashSet::new() };
+        let size = Self::step_size(&step, &live);
+        let entry = self.undo_tree.get_mut(node);
+        entry.step = step;
+        entry.size = size;
+    }
+    
+    // Undo back to where the branch of `node` starts then redo along it, none being the oldest state kept
+    fn jump_to_history(&mut self, node: Option<usize>) {
+        self.save_state();
+        while !self.undo_tree.is_ancestor(self.undo_tree.current(), node) {
+            self.undo();
+        }
+        self.undo_tree.select(node);
+        while self.undo_tree.current() != node {
+            self.redo();
         }
     }
 
@@ -821,12 +2742,6 @@ impl PaintApp {
         let size = if self.current_tool == Tool::Eraser { self.eraser_size } else { self.brush_size };
         let size_squared = size * size;
         
-        // Ensure active layer is visible before drawing
-        if self.current_state.active_layer_index < self.current_state.layers.len() && 
-           !self.current_state.layers[self.current_state.active_layer_index].visible {
-            return;
-        }
-        
         // Collect all points that need to be modified
         let mut pixels = Vec::new();
         for dy in -size..=size {
@@ -842,57 +2757,156 @@ impl PaintApp {
             }
         }
         
+        self.paint_pixels(pixels, fill_color);
+    }
+    
+    // Paint canvas pixels of the active layer or its mask with a color, None erasing them
+    fn paint_pixels(&mut self, pixels: Vec<(usize, usize)>, fill_color: Option<Color32>) {
+        let fill_color = fill_color.map(compositing::from_color32);
+        
+        // Ensure active layer is visible before drawing
+        if !self.current_state.can_paint_active_layer(self.editing_mask()) {
+            return;
+        }
+        
+        // Locks come from the whole group chain, look them up once
+        let locks = self.current_state.locks_of(self.current_state.active_layer_index);
+        if locks.pixels {
+            return;
+        }
+        
         // Process all pixels sequentially
+        let editing_mask = self.editing_mask();
         for (nx, ny) in pixels {
-            self.record_change(nx, ny, fill_color);
+            let new_color = match fill_color {
+                // Semi-transparent colors are blended over the pixel as it was before the stroke,
+                // so overlapping dabs of the same stroke don't build up
+                Some(color) if color[3] < 1.0 => {
+                    let current = if editing_mask {
+                        self.current_state.get_mask_from_active_layer(nx, ny).map(mask_color)
+                    } else {
+                        self.current_state.get_from_active_layer(nx, ny)
+                    };
+                    let base = *self.stroke_origin.entry((nx, ny)).or_insert(current);
+                    Some(self.current_state.blend_colors(base, color))
+                },
+                _ => fill_color,
+            };
+            if editing_mask {
+                self.record_mask_change(nx, ny, mask_value(new_color));
+            } else {
+                self.record_change(nx, ny, new_color, locks);
+            }
         }
         
         self.texture_dirty = true;
     }
 
+    // Outline and inside colors of a shape, the outline takes the color of the button used and
+    // the inside the other one when both are painted
+    fn shape_colors(&self, use_secondary: bool) -> (Color32, Color32) {
+        let (main, other) = if use_secondary {
+            (self.secondary_color, self.primary_color)
+        } else {
+            (self.primary_color, self.secondary_color)
+        };
+        (main, if self.shape_style == ShapeStyle::OutlineFill { other } else { main })
+    }
+    
+    fn shape_points(&self, kind: ShapeKind, bounds: (f32, f32, f32, f32)) -> Vec<(f32, f32)> {
+        shapes::shape_points(kind, bounds, self.polygon_sides, self.corner_radius as f32)
+    }
+    
+    // Paint a shape in the box given by `shapes::shape_bounds`, the outline with the brush
+    fn draw_shape(&mut self, kind: ShapeKind, bounds: (f32, f32, f32, f32), use_secondary: bool) {
+        let (outline_color, fill_color) = self.shape_colors(use_secondary);
+        // The inside stops at the middle of the outline so curves don't show it past the brush
+        let fill_bounds = if self.shape_style.outlined() { shapes::inset_bounds(bounds) } else { bounds };
+        if self.shape_style.filled() {
+            let points = self.shape_points(kind, fill_bounds);
+            let pixels = shapes::filled_pixels(&points, self.current_state.width, self.current_state.height);
+            self.paint_pixels(pixels, Some(fill_color));
+            // A semi-transparent outline blends over the inside
+            self.stroke_origin.clear();
+        }
+        if self.shape_style.outlined() {
+            let corners: Vec<(i32, i32)> = self.shape_points(kind, shapes::inset_bounds(bounds))
+                .into_iter()
+                .map(|(x, y)| (x.floor() as i32, y.floor() as i32))
+                .collect();
+            for (i, &corner) in corners.iter().enumerate() {
+                self.draw_line(corner, corners[(i + 1) % corners.len()], outline_color);
+            }
+        }
+        self.last_action_time = Instant::now();
+        self.texture_dirty = true;
+    }
+
+    // Paint the curve or polyline placed so far with the brush as one step
+    fn finish_path(&mut self) {
+        if let Some(draft) = self.path_draft.take() {
+            let color = if draft.use_secondary { self.secondary_color } else { self.primary_color };
+            let pixels: Vec<(i32, i32)> = draft.points()
+                .into_iter()
+                .map(|(x, y)| (x.floor() as i32, y.floor() as i32))
+                .collect();
+            if let [pixel] = pixels[..] {
+                self.draw_line(pixel, pixel, color);
+            }
+            for segment in pixels.windows(2) {
+                self.draw_line(segment[0], segment[1], color);
+            }
+            self.save_state();
+        }
+    }
+
     // Optimized paint bucket fill
     fn paint_bucket(&mut self, x: usize, y: usize, use_secondary: bool) {
         if x >= self.current_state.width || y >= self.current_state.height {
             return;
         }
         
+        // Locked layers refuse the whole fill
+        let locks = self.current_state.locks_of(self.current_state.active_layer_index);
+        if locks.pixels {
+            return;
+        }
+        
         // Ensure active layer is visible before filling
-        if self.current_state.active_layer_index < self.current_state.layers.len() && 
-           !self.current_state.layers[self.current_state.active_layer_index].visible {
+        if !self.current_state.can_paint_active_layer(self.editing_mask()) {
             return;
         }
         
-        let target_color = self.current_state.get_from_active_layer(x, y);
         let color = if use_secondary { self.secondary_color } else { self.primary_color };
         let fill_color = if self.current_tool == Tool::Eraser {
             None
         } else {
-            Some(color)
+            self.current_state.bit_depth.quantize(Some(compositing::from_color32(color)))
         };
         
-        if target_color == fill_color {
-            return;
-        }
-        
-        // Pre-allocate for better performance
-        let mut queue = VecDeque::with_capacity(1024);
-        let mut visited = vec![false; self.current_state.width * self.current_state.height];
-        queue.push_back((x, y));
-        
-        while let Some((cx, cy)) = queue.pop_front() {
-            let idx = cy * self.current_state.width + cx;
-            if visited[idx] || self.current_state.get_from_active_layer(cx, cy) != target_color {
-                continue;
+        if self.editing_mask() {
+            let state = &self.current_state;
+            let target_value = state.get_mask_from_active_layer(x, y);
+            let fill_value = mask_value(fill_color);
+            if target_value == Some(fill_value) {
+                return;
             }
             
-            visited[idx] = true;
-            self.record_change(cx, cy, fill_color);
+            let region = state.flood_region(x, y, |cx, cy| state.get_mask_from_active_layer(cx, cy) == target_value);
+            for (cx, cy) in region {
+                self.record_mask_change(cx, cy, fill_value);
+            }
+        } else {
+            let state = &self.current_state;
+            let target_color = state.get_from_active_layer(x, y);
+            if target_color == fill_color {
+                return;
+            }
             
-            // Add adjacent pixels to queue
-            if cx > 0 { queue.push_back((cx - 1, cy)); }
-            if cx + 1 < self.current_state.width { queue.push_back((cx + 1, cy)); }
-            if cy > 0 { queue.push_back((cx, cy - 1)); }
-            if cy + 1 < self.current_state.height { queue.push_back((cx, cy + 1)); }
+            let region = state.flood_region(x, y, |cx, cy| state.get_from_active_layer(cx, cy) == target_color);
+            for (cx, cy) in region {
+                self.record_change(cx, cy, fill_color, locks);
+            }
         }
         
         self.last_action_time = Instant::now();
@@ -918,20 +2932,35 @@ impl PaintApp {
             
             // Create the image data
             let mut image_data = vec![0_u8; width * height * 4];
+            let image = self.current_state.flatten();
+            let mut pixels: Vec<Option<compositing::Rgba>> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| image.get(x, y)).collect();
+            
+            // Values kept in the document profile are shown in sRGB
+            if let Some(profile) = &self.current_state.color_profile && self.current_state.profile_values {
+                let mut straight: Vec<[f32; 4]> = pixels.iter().map(|pixel| pixel.map_or(compositing::TRANSPARENT, compositing::unpremultiply)).collect();
+                profile.convert_to_srgb(&mut straight);
+                for (pixel, color) in pixels.iter_mut().zip(straight) {
+                    if let Some(pixel) = pixel {
+                        *pixel = compositing::premultiply(color);
+                    }
+                }
+            }
             
             // Process all pixels
             for y in 0..height {
                 for x in 0..width {
-                    let color = if let Some(pixel) = self.current_state.get(x, y) {
-                        pixel
+                    let checker_x = x / CHECKERBOARD_SIZE;
+                    let checker_y = y / CHECKERBOARD_SIZE;
+                    let checker = if (checker_x + checker_y) % 2 == 0 {
+                        Color32::from_gray(200)
                     } else {
-                        let checker_x = x / CHECKERBOARD_SIZE;
-                        let checker_y = y / CHECKERBOARD_SIZE;
-                        if (checker_x + checker_y) % 2 == 0 {
-                            Color32::from_gray(200)
-                        } else {
-                            Color32::from_gray(160)
-                        }
+                        Color32::from_gray(160)
+                    };
+                    
+                    // Blend the composited pixel over the checkerboard so transparency stays visible
+                    let color = match pixels[y * width + x] {
+                        Some(pixel) => compositing::to_color32(compositing::blend_colors(Some(compositing::from_color32(checker)), pixel)),
+                        None => checker,
                     };
                     
                     let idx = (y * width + x) * 4;
@@ -974,10 +3003,52 @@ enum PendingAction {
 // Action à effectuer sur les calques
 enum LayerAction {
     ToggleVisibility(usize),
+    ToggleCollapsed(usize),
     SetActive(usize),
     Edit(usize),
 }
 
+// Ligne du panneau des calques
+struct LayerRow {
+    index: usize,
+    name: String,
+    visible: bool,
+    is_active: bool,
+    depth: usize,
+    // Some(collapsed) for groups
+    collapsed: Option<bool>,
+    is_adjustment: bool,
+    clipped: bool,
+    // Some(enabled) when the layer has a mask
+    mask: Option<bool>,
+    locks: LayerLocks,
+}
+
+// Réglages de la boîte de dialogue de taille du canevas
+struct CanvasSizeDialog {
+    width: usize,
+    height: usize,
+    // Position du contenu actuel: 0 gauche/haut, 1 centre, 2 droite/bas
+    anchor: (usize, usize),
+}
+
+// Réglages de la boîte de dialogue de mise à l'échelle
+struct ScaleImageDialog {
+    width: usize,
+    height: usize,
+    keep_ratio: bool,
+    filter: ResampleFilter,
+}
+
+// Réglages de la boîte de dialogue de rotation libre
+struct RotateDialog {
+    // Degrés, sens horaire
+    angle: f32,
+    filter: ResampleFilter,
+    // Toute l'image (le canevas s'agrandit) ou seulement le calque actif
+    whole_image: bool,
+}
+
 // Main application struct
 struct MyApp {
     state: AppState,
@@ -986,6 +3057,10 @@ struct MyApp {
     new_layer_name: String,
     rename_layer_index: Option<usize>,
     rename_layer_name: String,
+    canvas_size_dialog: Option<CanvasSizeDialog>,
+    scale_image_dialog: Option<ScaleImageDialog>,
+    rotate_dialog: Option<RotateDialog>,
+    show_history: bool,
     pending_action: PendingAction,
     language: Language,
 }
@@ -999,51 +3074,184 @@ impl Default for MyApp {
             new_layer_name: "New Layer".to_string(),
             rename_layer_index: None,
             rename_layer_name: String::new(),
+            canvas_size_dialog: None,
+            scale_image_dialog: None,
+            rotate_dialog: None,
+            show_history: false,
             pending_action: PendingAction::None,
             language: Language::French,
         }
-    }
-}
-
-impl eframe::App for MyApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Process keyboard shortcuts
-        let ctrl = ctx.input(|i| i.modifiers.ctrl);
-        let shift = ctx.input(|i| i.modifiers.shift);
+    }
+}
+
+impl eframe::App for MyApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // Process keyboard shortcuts
+        let ctrl = ctx.input(|i| i.modifiers.ctrl);
+        let shift = ctx.input(|i| i.modifiers.shift);
+        
+        // Show error message dialog if needed
+        if self.show_error {
+            egui::Window::new(get_text("error", self.language))
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(self.error_message.as_deref().unwrap_or(&get_text("an_error_occurred", self.language)));
+                    if ui.button("OK").clicked() {
+                        self.show_error = false;
+                    }
+                });
+        }
+        
+        // Process rename layer dialog
+        if let Some(layer_idx) = self.rename_layer_index {
+            egui::Window::new(get_text("rename_layer", self.language))
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.text_edit_singleline(&mut self.rename_layer_name);
+                    ui.horizontal(|ui| {
+                        if ui.button("OK").clicked() && !self.rename_layer_name.is_empty() {
+                            if let AppState::Canvas(paint_app) = &mut self.state {
+                                paint_app.rename_layer(layer_idx, self.rename_layer_name.clone());
+                            }
+                            self.rename_layer_index = None;
+                        }
+                        if ui.button(get_text("cancel", self.language)).clicked() {
+                            self.rename_layer_index = None;
+                        }
+                    });
+                });
+        }
+        
+        // Process canvas size dialog
+        let mut close_canvas_size = false;
+        if let (Some(dialog), AppState::Canvas(paint_app)) = (&mut self.canvas_size_dialog, &mut self.state) {
+            egui::Window::new(get_text("canvas_size", self.language))
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(get_text("width", self.language));
+                        ui.add(egui::DragValue::new(&mut dialog.width).clamp_range(1..=10000));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label(get_text("height", self.language));
+                        ui.add(egui::DragValue::new(&mut dialog.height).clamp_range(1..=10000));
+                    });
+                    ui.label(get_text("anchor", self.language));
+                    egui::Grid::new("canvas_anchor").show(ui, |ui| {
+                        for ay in 0..3 {
+                            for ax in 0..3 {
+                                let selected = dialog.anchor == (ax, ay);
+                                if ui.selectable_label(selected, if selected { "●" } else { "○" }).clicked() {
+                                    dialog.anchor = (ax, ay);
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("OK").clicked() {
+                            paint_app.resize_canvas(dialog.width, dialog.height, dialog.anchor);
+                            close_canvas_size = true;
+                        }
+                        if ui.button(get_text("cancel", self.language)).clicked() {
+                            close_canvas_size = true;
+                        }
+                    });
+                });
+        }
+        if close_canvas_size {
+            self.canvas_size_dialog = None;
+        }
         
-        // Show error message dialog if needed
-        if self.show_error {
-            egui::Window::new(get_text("error", self.language))
+        // Process scale image dialog
+        let mut close_scale_image = false;
+        if let (Some(dialog), AppState::Canvas(paint_app)) = (&mut self.scale_image_dialog, &mut self.state) {
+            let (current_width, current_height) = (paint_app.current_state.width, paint_app.current_state.height);
+            egui::Window::new(get_text("scale_image", self.language))
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
-                    ui.label(self.error_message.as_deref().unwrap_or(&get_text("an_error_occurred", self.language)));
-                    if ui.button("OK").clicked() {
-                        self.show_error = false;
-                    }
+                    ui.horizontal(|ui| {
+                        ui.label(get_text("width", self.language));
+                        if ui.add(egui::DragValue::new(&mut dialog.width).clamp_range(1..=10000)).changed() && dialog.keep_ratio {
+                            dialog.height = ((dialog.width * current_height) as f32 / current_width as f32).round().max(1.0) as usize;
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label(get_text("height", self.language));
+                        if ui.add(egui::DragValue::new(&mut dialog.height).clamp_range(1..=10000)).changed() && dialog.keep_ratio {
+                            dialog.width = ((dialog.height * current_width) as f32 / current_height as f32).round().max(1.0) as usize;
+                        }
+                    });
+                    ui.checkbox(&mut dialog.keep_ratio, get_text("keep_ratio", self.language));
+                    ui.horizontal(|ui| {
+                        ui.label(get_text("interpolation", self.language));
+                        egui::ComboBox::from_id_source("resample_filter")
+                            .selected_text(get_text(dialog.filter.text_key(), self.language))
+                            .show_ui(ui, |ui| {
+                                for filter in ResampleFilter::ALL {
+                                    ui.selectable_value(&mut dialog.filter, filter, get_text(filter.text_key(), self.language));
+                                }
+                            });
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("OK").clicked() {
+                            paint_app.scale_image(dialog.width, dialog.height, dialog.filter);
+                            close_scale_image = true;
+                        }
+                        if ui.button(get_text("cancel", self.language)).clicked() {
+                            close_scale_image = true;
+                        }
+                    });
                 });
         }
+        if close_scale_image {
+            self.scale_image_dialog = None;
+        }
         
-        // Process rename layer dialog
-        if let Some(layer_idx) = self.rename_layer_index {
-            egui::Window::new(get_text("rename_layer", self.language))
+        // Process rotate dialog
+        let mut close_rotate = false;
+        if let (Some(dialog), AppState::Canvas(paint_app)) = (&mut self.rotate_dialog, &mut self.state) {
+            let title = if dialog.whole_image { "rotate_image" } else { "rotate_layer" };
+            egui::Window::new(get_text(title, self.language))
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
-                    ui.text_edit_singleline(&mut self.rename_layer_name);
                     ui.horizontal(|ui| {
-                        if ui.button("OK").clicked() && !self.rename_layer_name.is_empty() {
-                            if let AppState::Canvas(paint_app) = &mut self.state {
-                                paint_app.rename_layer(layer_idx, self.rename_layer_name.clone());
+                        ui.label(get_text("angle", self.language));
+                        ui.add(egui::DragValue::new(&mut dialog.angle).speed(0.5).clamp_range(-360.0..=360.0).suffix("°"));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label(get_text("interpolation", self.language));
+                        egui::ComboBox::from_id_source("rotate_filter")
+                            .selected_text(get_text(dialog.filter.text_key(), self.language))
+                            .show_ui(ui, |ui| {
+                                for filter in ResampleFilter::ALL {
+                                    ui.selectable_value(&mut dialog.filter, filter, get_text(filter.text_key(), self.language));
+                                }
+                            });
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("OK").clicked() {
+                            if dialog.whole_image {
+                                paint_app.rotate_image(dialog.angle, dialog.filter);
+                            } else {
+                                paint_app.rotate_layer(paint_app.current_state.active_layer_index, dialog.angle, dialog.filter);
                             }
-                            self.rename_layer_index = None;
+                            close_rotate = true;
                         }
                         if ui.button(get_text("cancel", self.language)).clicked() {
-                            self.rename_layer_index = None;
+                            close_rotate = true;
                         }
                     });
                 });
         }
+        if close_rotate {
+            self.rotate_dialog = None;
+        }
         
         // Process pending actions
         match &self.pending_action {
@@ -1057,6 +3265,9 @@ impl eframe::App for MyApp {
                         LayerAction::ToggleVisibility(idx) => {
                             paint_app.toggle_layer_visibility(*idx);
                         },
+                        LayerAction::ToggleCollapsed(idx) => {
+                            paint_app.toggle_group_collapsed(*idx);
+                        },
                         LayerAction::SetActive(idx) => {
                             paint_app.set_active_layer(*idx);
                         },
@@ -1091,10 +3302,10 @@ impl eframe::App for MyApp {
                     match result {
                         main_menu::MenuResult::Action(action) => {
                             match action {
-                                main_menu::MenuAction::NewCanvas(width, height) => {
-                                    self.state = AppState::Canvas(PaintApp::new(width, height, self.language));
+                                main_menu::MenuAction::NewCanvas(width, height, bit_depth) => {
+                                    self.state = AppState::Canvas(PaintApp::new(width, height, bit_depth, self.language));
                                 },
-                                main_menu::MenuAction::OpenFile => {
+                                main_menu::MenuAction::OpenFile(convert_profile) => {
                                     if let Some(path) = FileDialog::new()
                                         .add_filter("All Supported Files", &["png", "jpg", "jpeg", "bmp", "tiff", "tif", "gif", "webp", "rustiq"])
                                         .add_filter("PNG Image", &["png"])
@@ -1106,7 +3317,7 @@ impl eframe::App for MyApp {
                                         .add_filter("Rustique File", &["rustiq"])
                                         .set_directory("/")
                                         .pick_file() {
-                                        match PaintApp::open_file(path.to_str().unwrap(), self.language) {
+                                        match PaintApp::open_file(path.to_str().unwrap(), self.language, convert_profile) {
                                             Ok(app) => self.state = AppState::Canvas(app),
                                             Err(e) => {
                                                 self.error_message = Some(e);
@@ -1244,6 +3455,13 @@ impl eframe::App for MyApp {
                             if ui.button("-").clicked() && paint_app.current_state.layers.len() > 1 {
                                 paint_app.remove_layer(paint_app.current_state.active_layer_index);
                             }
+                            if ui.button(get_text("group", self.language)).clicked() {
+                                let group_count = paint_app.current_state.layers.iter().filter(|layer| layer.is_group()).count();
+                                paint_app.group_layer(
+                                    paint_app.current_state.active_layer_index,
+                                    format!("{} {}", get_text("group", self.language), group_count + 1)
+                                );
+                            }
                             ui.add_space(5.0);
                             if ui.button(get_text("up", self.language)).clicked() {
                                 paint_app.move_layer_up(paint_app.current_state.active_layer_index);
@@ -1252,39 +3470,233 @@ impl eframe::App for MyApp {
                                 paint_app.move_layer_down(paint_app.current_state.active_layer_index);
                             }
                         });
+                        ui.horizontal(|ui| {
+                            if ui.button("⧉").on_hover_text(get_text("duplicate_layer", self.language)).clicked() {
+                                paint_app.duplicate_layer(paint_app.current_state.active_layer_index, &get_text("copy", self.language));
+                            }
+                            if ui.button("⤓").on_hover_text(get_text("merge_down", self.language)).clicked() {
+                                paint_app.merge_down(paint_app.current_state.active_layer_index);
+                            }
+                            if ui.button(get_text("merge_visible", self.language)).clicked() {
+                                paint_app.merge_visible(get_text("merged", self.language));
+                            }
+                            if ui.button(get_text("flatten", self.language)).clicked() {
+                                paint_app.flatten_image(get_text("background", self.language));
+                            }
+                        });
+                        ui.menu_button(get_text("new_adjustment", self.language), |ui| {
+                            for adjustment in Adjustment::defaults() {
+                                let name = get_text(adjustment.text_key(), self.language);
+                                if ui.button(&name).clicked() {
+                                    paint_app.add_adjustment_layer(name, adjustment);
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                        
+                        // Opacity and blend mode of the active layer
+                        let active_index = paint_app.current_state.active_layer_index;
+                        let active_settings = paint_app.current_state.layers
+                            .get(active_index)
+                            .map(|layer| (layer.opacity, layer.blend_mode));
+                        if let Some((current_opacity, current_blend_mode)) = active_settings {
+                            let mut opacity = current_opacity * 100.0;
+                            let mut blend_mode = current_blend_mode;
+                            ui.horizontal(|ui| {
+                                ui.label(get_text("opacity", self.language));
+                                // A slider drag is a single history step
+                                let slider = ui.add(egui::Slider::new(&mut opacity, 0.0..=100.0).suffix("%"));
+                                if slider.drag_started() {
+                                    paint_app.begin_layer_edit();
+                                }
+                                if slider.changed() {
+                                    paint_app.set_layer_opacity(active_index, opacity / 100.0);
+                                }
+                                if slider.drag_released() {
+                                    paint_app.end_layer_edit(HistoryAction::LayerOpacity);
+                                }
+                            });
+                            let mut clipped = paint_app.current_state.layers[active_index].clipped;
+                            if ui.checkbox(&mut clipped, get_text("clip_to_below", self.language)).changed() {
+                                paint_app.toggle_layer_clipping(active_index);
+                            }
+                            ui.horizontal(|ui| {
+                                ui.label(get_text("blend_mode", self.language));
+                                egui::ComboBox::from_id_source("blend_mode")
+                                    .selected_text(get_text(blend_mode.text_key(), self.language))
+                                    .show_ui(ui, |ui| {
+                                        for mode in BlendMode::ALL {
+                                            ui.selectable_value(&mut blend_mode, mode, get_text(mode.text_key(), self.language));
+                                        }
+                                    });
+                            });
+                            if blend_mode != current_blend_mode {
+                                paint_app.set_layer_blend_mode(active_index, blend_mode);
+                            }
+                        }
+                        
+                        // Settings of the active adjustment layer
+                        let active_adjustment = match paint_app.current_state.layers.get(active_index).map(|layer| &layer.kind) {
+                            Some(LayerKind::Adjustment(adjustment)) => Some(adjustment.clone()),
+                            _ => None,
+                        };
+                        if let Some(mut adjustment) = active_adjustment {
+                            ui.label(get_text(adjustment.text_key(), self.language));
+                            let language = self.language;
+                            let edit = adjustments::adjustment_editor(ui, &mut adjustment, |key| get_text(key, language));
+                            if edit.drag_started {
+                                paint_app.begin_layer_edit();
+                            }
+                            if edit.changed {
+                                paint_app.set_layer_adjustment(active_index, adjustment);
+                            }
+                            if edit.drag_released {
+                                paint_app.end_layer_edit(HistoryAction::AdjustLayer);
+                            }
+                        }
+                        
+                        // Mask of the active layer
+                        let mask_enabled = paint_app.current_state.layers
+                            .get(active_index)
+                            .and_then(|layer| layer.mask.as_ref())
+                            .map(|mask| mask.enabled);
+                        ui.horizontal(|ui| {
+                            ui.label(get_text("mask", self.language));
+                            match mask_enabled {
+                                None => {
+                                    if ui.button(get_text("add_mask", self.language)).clicked() {
+                                        paint_app.add_layer_mask(active_index);
+                                    }
+                                },
+                                Some(enabled) => {
+                                    let mut checked = enabled;
+                                    if ui.checkbox(&mut checked, "").on_hover_text(get_text("enable_mask", self.language)).changed() {
+                                        paint_app.toggle_layer_mask(active_index);
+                                    }
+                                    let editing_mask = paint_app.edit_target == EditTarget::Mask;
+                                    if ui.selectable_label(editing_mask, get_text("edit_mask", self.language)).clicked() {
+                                        paint_app.edit_target = if editing_mask { EditTarget::Pixels } else { EditTarget::Mask };
+                                    }
+                                    if ui.small_button(get_text("apply_mask", self.language)).clicked() {
+                                        paint_app.apply_layer_mask(active_index);
+                                    }
+                                    if ui.small_button("🗑").on_hover_text(get_text("delete_mask", self.language)).clicked() {
+                                        paint_app.delete_layer_mask(active_index);
+                                    }
+                                }
+                            }
+                        });
+                        
+                        // Locks of the active layer
+                        if let Some(current_locks) = paint_app.current_state.layers.get(active_index).map(|layer| layer.locks) {
+                            let mut locks = current_locks;
+                            ui.horizontal(|ui| {
+                                ui.label(get_text("lock", self.language));
+                                ui.toggle_value(&mut locks.alpha, "▦").on_hover_text(get_text("lock_alpha", self.language));
+                                ui.toggle_value(&mut locks.pixels, "🖌").on_hover_text(get_text("lock_pixels", self.language));
+                                ui.toggle_value(&mut locks.position, "✥").on_hover_text(get_text("lock_position", self.language));
+                                let mut all = locks.is_all();
+                                if ui.toggle_value(&mut all, "🔒").on_hover_text(get_text("lock_all", self.language)).changed() {
+                                    locks = if all { LayerLocks::ALL } else { LayerLocks::default() };
+                                }
+                            });
+                            if locks != current_locks {
+                                paint_app.set_layer_locks(active_index, locks);
+                            }
+                        }
                         
                         ui.separator();
                         
                         // Layer list - clone the data to avoid borrowing issues
                         // This avoids the conflict between immutable and mutable borrows
-                        let layers_info: Vec<(usize, String, bool, bool)> = paint_app.current_state.layers
+                        // Layers hidden inside collapsed groups are skipped
+                        let state = &paint_app.current_state;
+                        let layers_info: Vec<LayerRow> = state.layers
                             .iter()
                             .enumerate()
-                            .map(|(i, layer)| (i, layer.name.clone(), layer.visible, i == paint_app.current_state.active_layer_index))
+                            .filter(|(i, _)| !state.is_folded(*i))
+                            .map(|(i, layer)| {
+                                let name = if layer.opacity < 1.0 {
+                                    format!("{} ({:.0}%)", layer.name, layer.opacity * 100.0)
+                                } else {
+                                    layer.name.clone()
+                                };
+                                LayerRow {
+                                    index: i,
+                                    name,
+                                    visible: layer.visible,
+                                    is_active: i == state.active_layer_index,
+                                    depth: layer.depth,
+                                    collapsed: match layer.kind {
+                                        LayerKind::Group { collapsed } => Some(collapsed),
+                                        _ => None,
+                                    },
+                                    is_adjustment: matches!(layer.kind, LayerKind::Adjustment(_)),
+                                    clipped: layer.clipped,
+                                    mask: layer.mask.as_ref().map(|mask| mask.enabled),
+                                    locks: state.locks_of(i),
+                                }
+                            })
                             .collect();
                         
                         // Display the layers in reverse order (top layer first visually)
-                        for (i, name, visible, is_active) in layers_info.iter().rev() {
+                        for row in layers_info.iter().rev() {
+                            let i = row.index;
                             ui.horizontal(|ui| {
-                                let visible_text = if *visible { "👁" } else { "⊘" };
+                                ui.add_space(row.depth as f32 * 12.0);
+                                // Clipped layers hang from their base layer
+                                if row.clipped {
+                                    ui.label("↳");
+                                }
+                                
+                                let visible_text = if row.visible { "👁" } else { "⊘" };
                                 if ui.button(visible_text).clicked() {
                                     self.pending_action = PendingAction::HandleLayerAction(
-                                        LayerAction::ToggleVisibility(*i)
+                                        LayerAction::ToggleVisibility(i)
                                     );
                                 }
                                 
-                                if ui.selectable_label(*is_active, name).clicked() {
+                                if let Some(collapsed) = row.collapsed {
+                                    let fold_text = if collapsed { "▶" } else { "▼" };
+                                    if ui.small_button(fold_text).clicked() {
+                                        self.pending_action = PendingAction::HandleLayerAction(
+                                            LayerAction::ToggleCollapsed(i)
+                                        );
+                                    }
+                                }
+                                
+                                let label = if row.collapsed.is_some() {
+                                    format!("📁 {}", row.name)
+                                } else if row.is_adjustment {
+                                    format!("⚙ {}", row.name)
+                                } else {
+                                    row.name.clone()
+                                };
+                                if ui.selectable_label(row.is_active, label).clicked() {
                                     self.pending_action = PendingAction::HandleLayerAction(
-                                        LayerAction::SetActive(*i)
+                                        LayerAction::SetActive(i)
                                     );
                                 }
                                 
                                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                                     if ui.small_button("✏").clicked() {
                                         self.pending_action = PendingAction::HandleLayerAction(
-                                            LayerAction::Edit(*i)
+                                            LayerAction::Edit(i)
                                         );
                                     }
+                                    // Fully locked layers are clearly flagged, partial locks stay discreet
+                                    if row.locks.is_all() {
+                                        ui.label(egui::RichText::new("🔒").color(Color32::from_rgb(230, 160, 60)))
+                                            .on_hover_text(get_text("layer_locked", self.language));
+                                    } else if row.locks.any() {
+                                        ui.label(egui::RichText::new("🔒").weak())
+                                            .on_hover_text(get_text("lock", self.language));
+                                    }
+                                    if let Some(enabled) = row.mask {
+                                        let mask_text = egui::RichText::new("◐");
+                                        ui.label(if enabled { mask_text } else { mask_text.weak() })
+                                            .on_hover_text(get_text("mask", self.language));
+                                    }
                                 });
                             });
                         }
@@ -1309,6 +3721,23 @@ impl eframe::App for MyApp {
                         if ui.button(get_text("line", self.language)).clicked() {
                             paint_app.current_tool = Tool::Line;
                         }
+                        if ui.button(get_text("curve", self.language)).clicked() {
+                            paint_app.current_tool = Tool::Curve;
+                        }
+                        if ui.button(get_text("polyline", self.language)).clicked() {
+                            paint_app.current_tool = Tool::Polyline;
+                        }
+                        for kind in ShapeKind::ALL {
+                            if ui.button(get_text(kind.text_key(), self.language)).clicked() {
+                                paint_app.current_tool = Tool::Shape(kind);
+                            }
+                        }
+                        if ui.button(get_text("select", self.language)).clicked() {
+                            paint_app.current_tool = Tool::Select;
+                        }
+                        if ui.button(get_text("move", self.language)).clicked() {
+                            paint_app.current_tool = Tool::Move;
+                        }
                         
                         ui.separator();
                         ui.label(get_text("save_options", self.language));
@@ -1333,6 +3762,8 @@ impl eframe::App for MyApp {
                                 }
                             }
                         }
+                        ui.checkbox(&mut paint_app.save_history, get_text("include_history", self.language))
+                            .on_hover_text(get_text("include_history_hint", self.language));
                         
                         ui.separator();
                         
@@ -1344,6 +3775,31 @@ impl eframe::App for MyApp {
                         ui.label(get_text("eraser_size", self.language));
                         ui.add(egui::DragValue::new(&mut paint_app.eraser_size).speed(0.1).clamp_range(1..=500));
                         
+                        // Réglages des formes, le contour utilise la taille du pinceau
+                        if let Tool::Shape(kind) = paint_app.current_tool {
+                            ui.add_space(10.0);
+                            ui.label(get_text("shape_style", self.language));
+                            for style in ShapeStyle::ALL {
+                                ui.radio_value(&mut paint_app.shape_style, style, get_text(style.text_key(), self.language));
+                            }
+                            if kind == ShapeKind::RoundedRectangle {
+                                ui.label(get_text("corner_radius", self.language));
+                                ui.add(egui::DragValue::new(&mut paint_app.corner_radius).clamp_range(0..=1000));
+                            }
+                            if kind == ShapeKind::Polygon {
+                                ui.label(get_text("polygon_sides", self.language));
+                                ui.add(egui::DragValue::new(&mut paint_app.polygon_sides).clamp_range(3..=64));
+                            }
+                            ui.label(egui::RichText::new(get_text("shape_hint", self.language)).weak());
+                        }
+                        if paint_app.current_tool == Tool::Curve {
+                            ui.add_space(10.0);
+                            ui.label(egui::RichText::new(get_text("curve_hint", self.language)).weak());
+                        }
+                        if matches!(paint_app.current_tool, Tool::Curve | Tool::Polyline) {
+                            ui.label(egui::RichText::new(get_text("path_hint", self.language)).weak());
+                        }
+                        
                         ui.add_space(10.0);
                         ui.label(get_text("colors", self.language));
                         ui.horizontal(|ui| {
@@ -1408,6 +3864,52 @@ impl eframe::App for MyApp {
                     });
                 });
 
+                // History panel, undone steps and abandoned branches stay listed greyed
+                if self.show_history {
+                    egui::SidePanel::right("history_panel").show(ctx, |ui| {
+                        ui.heading(get_text("history", self.language));
+                        
+                        // Memory used by the steps against the budget, in megabytes
+                        let megabytes = get_text("megabytes", self.language);
+                        let mut budget = paint_app.history_budget / MEGABYTE;
+                        ui.horizontal(|ui| {
+                            ui.label(get_text("history_memory", self.language));
+                            let drag = egui::DragValue::new(&mut budget).clamp_range(16..=16384).suffix(format!(" {}", megabytes));
+                            if ui.add(drag).changed() {
+                                paint_app.set_history_budget(budget * MEGABYTE);
+                            }
+                        });
+                        ui.label(format!("{} {:.1} {}", get_text("history_used", self.language), paint_app.history_size() as f32 / MEGABYTE as f32, megabytes));
+                        ui.separator();
+                        
+                        // Steps of abandoned branches are indented under the step they start from
+                        let current = paint_app.undo_tree.current();
+                        let applied: HashSet<usize> = paint_app.undo_tree.path(current).into_iter().collect();
+                        let mut jump_to = None;
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            if ui.selectable_label(current.is_none(), get_text("history_start", self.language)).clicked() {
+                                jump_to = Some(None);
+                            }
+                            for (node, depth) in paint_app.undo_tree.rows() {
+                                let entry = paint_app.undo_tree.get(node);
+                                let mut text = egui::RichText::new(get_text(entry.action.text_key(), self.language));
+                                if !applied.contains(&node) {
+                                    text = text.weak();
+                                }
+                                ui.horizontal(|ui| {
+                                    ui.add_space(depth as f32 * 12.0);
+                                    if ui.selectable_label(current == Some(node), text).clicked() {
+                                        jump_to = Some(Some(node));
+                                    }
+                                });
+                            }
+                        });
+                        if let Some(node) = jump_to {
+                            paint_app.jump_to_history(node);
+                        }
+                    });
+                }
+
                 // Top panel for buttons
                 let (undo_clicked, redo_clicked, return_to_menu_clicked) = egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
                     let mut return_clicked = false;
@@ -1426,6 +3928,82 @@ impl eframe::App for MyApp {
                         if ui.button(get_text("redo", self.language)).clicked() {
                             redo_clicked = true;
                         }
+                        ui.toggle_value(&mut self.show_history, get_text("history", self.language));
+                        
+                        ui.menu_button(get_text("image", self.language), |ui| {
+                            if ui.button(get_text("canvas_size", self.language)).clicked() {
+                                self.canvas_size_dialog = Some(CanvasSizeDialog {
+                                    width: paint_app.current_state.width,
+                                    height: paint_app.current_state.height,
+                                    anchor: (1, 1),
+                                });
+                                ui.close_menu();
+                            }
+                            if ui.button(get_text("scale_image", self.language)).clicked() {
+                                self.scale_image_dialog = Some(ScaleImageDialog {
+                                    width: paint_app.current_state.width,
+                                    height: paint_app.current_state.height,
+                                    keep_ratio: true,
+                                    filter: ResampleFilter::Bicubic,
+                                });
+                                ui.close_menu();
+                            }
+                            if ui.add_enabled(paint_app.selection.is_some(), egui::Button::new(get_text("crop_to_selection", self.language))).clicked() {
+                                paint_app.crop_to_selection();
+                                ui.close_menu();
+                            }
+                            if ui.button(get_text("trim", self.language)).clicked() {
+                                paint_app.trim_canvas();
+                                ui.close_menu();
+                            }
+                            ui.separator();
+                            for orientation in Orientation::ALL {
+                                if ui.button(get_text(orientation.text_key(), self.language)).clicked() {
+                                    paint_app.orient_image(orientation);
+                                    ui.close_menu();
+                                }
+                            }
+                            if ui.button(get_text("rotate_free", self.language)).clicked() {
+                                self.rotate_dialog = Some(RotateDialog { angle: 0.0, filter: ResampleFilter::Bicubic, whole_image: true });
+                                ui.close_menu();
+                            }
+                            ui.separator();
+                            ui.menu_button(get_text("color_profile", self.language), |ui| {
+                                let name = paint_app.current_state.color_profile.as_ref().map_or("sRGB", ColorProfile::name);
+                                ui.label(format!("{} {}", get_text("export_profile", self.language), name));
+                                if ui.button(get_text("use_srgb", self.language)).clicked() {
+                                    paint_app.set_color_profile(None);
+                                    ui.close_menu();
+                                }
+                                if ui.button(get_text("load_color_profile", self.language)).clicked() {
+                                    let path = FileDialog::new().add_filter("ICC Profile", &["icc", "icm"]).pick_file();
+                                    if let Some(Err(e)) = path.map(|path| paint_app.load_color_profile(&path)) {
+                                        self.error_message = Some(e);
+                                        self.show_error = true;
+                                    }
+                                    ui.close_menu();
+                                }
+                            });
+                            let mut linear = paint_app.current_state.linear_blending;
+                            if ui.checkbox(&mut linear, get_text("linear_blending", self.language)).changed() {
+                                paint_app.set_linear_blending(linear);
+                            }
+                        });
+                        
+                        ui.menu_button(get_text("layer", self.language), |ui| {
+                            let active_index = paint_app.current_state.active_layer_index;
+                            let movable = paint_app.transformable_layers(active_index).is_some();
+                            for orientation in Orientation::ALL {
+                                if ui.add_enabled(movable, egui::Button::new(get_text(orientation.text_key(), self.language))).clicked() {
+                                    paint_app.orient_layer(active_index, orientation);
+                                    ui.close_menu();
+                                }
+                            }
+                            if ui.add_enabled(movable, egui::Button::new(get_text("rotate_free", self.language))).clicked() {
+                                self.rotate_dialog = Some(RotateDialog { angle: 0.0, filter: ResampleFilter::Bicubic, whole_image: false });
+                                ui.close_menu();
+                            }
+                        });
                         ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                             ui.label(get_text("shortcuts_info", self.language));
                         });
@@ -1551,6 +4129,197 @@ impl eframe::App for MyApp {
                             paint_app.line_end = None;
                             paint_app.is_first_click_line = true;
                         }
+                    } else if matches!(paint_app.current_tool, Tool::Curve | Tool::Polyline) {
+                        // Clicks place anchors. With the curve tool, dragging from a new anchor pulls out its
+                        // handles, mirrored unless Alt is held, and anchors or handles can be dragged again.
+                        let curve = paint_app.current_tool == Tool::Curve;
+                        let to_point = |pos: Pos2| {
+                            let canvas_pos = to_canvas.transform_pos(pos);
+                            (canvas_pos.x, canvas_pos.y)
+                        };
+                        // Grips are picked a few screen pixels around
+                        let reach = 6.0 * canvas_width / canvas_rect.width();
+                        let mirror = !ctx.input(|i| i.modifiers.alt);
+                        let place_anchor = |paint_app: &mut PaintApp, point: (f32, f32), use_secondary: bool| {
+                            let draft = paint_app.path_draft.get_or_insert_with(|| PathDraft {
+                                anchors: Vec::new(),
+                                use_secondary,
+                                dragging: None,
+                            });
+                            draft.anchors.push(PathAnchor { point, handle_in: point, handle_out: point });
+                            draft.anchors.len() - 1
+                        };
+                        
+                        let secondary_started = response.drag_started_by(egui::PointerButton::Secondary);
+                        if (response.drag_started_by(egui::PointerButton::Primary) || secondary_started)
+                            && let Some(origin) = ctx.input(|i| i.pointer.press_origin()).map(to_point) {
+                            let grip = paint_app.path_draft.as_ref()
+                                .and_then(|draft| draft.grip_at(origin, reach))
+                                .filter(|&grip| curve || matches!(grip, PathGrip::Point(_)));
+                            let grip = grip.unwrap_or_else(|| {
+                                let index = place_anchor(paint_app, origin, secondary_started);
+                                if curve { PathGrip::HandleOut(index) } else { PathGrip::Point(index) }
+                            });
+                            if let Some(draft) = &mut paint_app.path_draft {
+                                draft.dragging = Some(grip);
+                            }
+                        }
+                        if let (Some(draft), Some(pos)) = (&mut paint_app.path_draft, response.interact_pointer_pos())
+                            && let Some(grip) = draft.dragging {
+                            draft.drag(grip, to_point(pos), mirror);
+                        }
+                        if response.drag_released() && let Some(draft) = &mut paint_app.path_draft {
+                            draft.dragging = None;
+                        }
+                        let secondary_clicked = response.clicked_by(egui::PointerButton::Secondary);
+                        if (response.clicked_by(egui::PointerButton::Primary) || secondary_clicked)
+                            && let Some(pos) = response.interact_pointer_pos().map(to_point) {
+                            let on_grip = paint_app.path_draft.as_ref().is_some_and(|draft| draft.grip_at(pos, reach).is_some());
+                            if !on_grip {
+                                place_anchor(paint_app, pos, secondary_clicked);
+                            }
+                        }
+                        
+                        // Enter or Escape paints the path, Backspace removes the last anchor and the middle button cancels
+                        if !ctx.wants_keyboard_input() {
+                            if ctx.input(|i| i.key_pressed(egui::Key::Enter) || i.key_pressed(egui::Key::Escape)) {
+                                paint_app.finish_path();
+                            }
+                            if ctx.input(|i| i.key_pressed(egui::Key::Backspace)) && let Some(draft) = &mut paint_app.path_draft {
+                                draft.anchors.pop();
+                                draft.dragging = None;
+                                if draft.anchors.is_empty() {
+                                    paint_app.path_draft = None;
+                                }
+                            }
+                        }
+                        if response.clicked_by(egui::PointerButton::Middle) {
+                            paint_app.path_draft = None;
+                        }
+                        
+                        // Preview of the path with the brush width, the next segment follows the pointer
+                        if let Some(draft) = &paint_app.path_draft {
+                            let to_screen = |(x, y): (f32, f32)| to_canvas.inverse().transform_pos(Pos2::new(x, y));
+                            let color = if draft.use_secondary { paint_app.secondary_color } else { paint_app.primary_color };
+                            let width = (2 * paint_app.brush_size + 1) as f32 * canvas_rect.width() / canvas_width;
+                            let mut points: Vec<Pos2> = draft.points().into_iter().map(to_screen).collect();
+                            if draft.dragging.is_none() && let Some(pos) = response.hover_pos() {
+                                points.push(pos);
+                            }
+                            painter.add(egui::Shape::line(points, Stroke::new(width, color)));
+                            
+                            let guide = Stroke::new(1.0, Color32::GRAY);
+                            for anchor in &draft.anchors {
+                                let point = to_screen(anchor.point);
+                                for handle in [anchor.handle_in, anchor.handle_out] {
+                                    if handle != anchor.point {
+                                        painter.line_segment([point, to_screen(handle)], guide);
+                                        painter.circle_filled(to_screen(handle), 3.0, Color32::GRAY);
+                                    }
+                                }
+                                painter.rect_stroke(Rect::from_center_size(point, Vec2::splat(7.0)), 0.0, Stroke::new(3.0, Color32::BLACK));
+                                painter.rect_stroke(Rect::from_center_size(point, Vec2::splat(7.0)), 0.0, Stroke::new(1.0, Color32::WHITE));
+                            }
+                        }
+                    } else if let Tool::Shape(kind) = paint_app.current_tool {
+                        // Drag a box from a corner, Shift makes the sides equal and Alt starts from the center
+                        let (square, from_center) = ctx.input(|i| (i.modifiers.shift, i.modifiers.alt));
+                        let to_pixel = |pos: Pos2| {
+                            let canvas_pos = to_canvas.transform_pos(pos);
+                            (canvas_pos.x.floor() as i32, canvas_pos.y.floor() as i32)
+                        };
+                        let secondary_started = response.drag_started_by(egui::PointerButton::Secondary);
+                        if (response.drag_started_by(egui::PointerButton::Primary) || secondary_started)
+                            && let Some(start) = ctx.input(|i| i.pointer.press_origin()).map(to_pixel) {
+                            paint_app.shape_drag = Some(ShapeDrag { start, end: start, use_secondary: secondary_started });
+                        }
+                        if let (Some(drag), Some(pos)) = (&mut paint_app.shape_drag, response.interact_pointer_pos()) {
+                            drag.end = to_pixel(pos);
+                        }
+                        if response.drag_released() && let Some(drag) = paint_app.shape_drag.take() {
+                            let bounds = shapes::shape_bounds(drag.start, drag.end, square, from_center);
+                            paint_app.draw_shape(kind, bounds, drag.use_secondary);
+                            paint_app.save_state();
+                        }
+                        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+                            paint_app.shape_drag = None;
+                        }
+                        
+                        // Preview of the shape being dragged
+                        if let Some(drag) = &paint_app.shape_drag {
+                            let bounds = shapes::shape_bounds(drag.start, drag.end, square, from_center);
+                            let (outline_color, fill_color) = paint_app.shape_colors(drag.use_secondary);
+                            let to_screen = |points: Vec<(f32, f32)>| -> Vec<Pos2> {
+                                points.into_iter().map(|(x, y)| to_canvas.inverse().transform_pos(Pos2::new(x, y))).collect()
+                            };
+                            if paint_app.shape_style.filled() {
+                                let fill_bounds = if paint_app.shape_style.outlined() { shapes::inset_bounds(bounds) } else { bounds };
+                                let points = to_screen(paint_app.shape_points(kind, fill_bounds));
+                                painter.add(egui::Shape::convex_polygon(points, fill_color, Stroke::NONE));
+                            }
+                            if paint_app.shape_style.outlined() {
+                                let points = to_screen(paint_app.shape_points(kind, shapes::inset_bounds(bounds)));
+                                let width = (2 * paint_app.brush_size + 1) as f32 * canvas_rect.width() / canvas_width;
+                                painter.add(egui::Shape::closed_line(points, Stroke::new(width, outline_color)));
+                            }
+                        }
+                    } else if paint_app.current_tool == Tool::Select {
+                        // Drag a rectangle, a simple click clears the selection
+                        let to_pixel = |pos: Pos2| {
+                            let canvas_pos = to_canvas.transform_pos(pos);
+                            (
+                                canvas_pos.x.clamp(0.0, canvas_width - 1.0) as usize,
+                                canvas_pos.y.clamp(0.0, canvas_height - 1.0) as usize,
+                            )
+                        };
+                        if response.drag_started_by(egui::PointerButton::Primary) {
+                            paint_app.selection_start = ctx.input(|i| i.pointer.press_origin()).map(to_pixel);
+                        }
+                        if response.dragged_by(egui::PointerButton::Primary) {
+                            if let (Some(start), Some(pos)) = (paint_app.selection_start, response.interact_pointer_pos()) {
+                                paint_app.selection = Some(Selection::from_corners(start, to_pixel(pos)));
+                            }
+                        } else if response.clicked_by(egui::PointerButton::Primary) {
+                            paint_app.selection = None;
+                        }
+                        if response.drag_released() {
+                            paint_app.selection_start = None;
+                        }
+                        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+                            paint_app.selection = None;
+                        }
+                    } else if paint_app.current_tool == Tool::Move {
+                        // Drag the active layer, the offset follows the pointer from where it was pressed
+                        if response.drag_started_by(egui::PointerButton::Primary) {
+                            paint_app.begin_move();
+                        }
+                        if response.dragged_by(egui::PointerButton::Primary) {
+                            let origin = ctx.input(|i| i.pointer.press_origin());
+                            if let (Some(origin), Some(pos)) = (origin, response.interact_pointer_pos()) {
+                                let delta = to_canvas.transform_pos(pos) - to_canvas.transform_pos(origin);
+                                paint_app.set_move_offset((delta.x.round() as i32, delta.y.round() as i32));
+                            }
+                        }
+                        if response.drag_released() {
+                            paint_app.commit_move();
+                        }
+                        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+                            paint_app.cancel_move();
+                        }
+                        
+                        // Arrow keys nudge by 1 pixel, 10 with Shift
+                        let step = if shift { 10 } else { 1 };
+                        let nudges = [
+                            (egui::Key::ArrowLeft, (-step, 0)),
+                            (egui::Key::ArrowRight, (step, 0)),
+                            (egui::Key::ArrowUp, (0, -step)),
+                            (egui::Key::ArrowDown, (0, step)),
+                        ];
+                        for (key, (dx, dy)) in nudges {
+                            if ctx.input(|i| i.key_pressed(key)) && !ctx.wants_keyboard_input() {
+                                paint_app.nudge_active_layer(dx, dy);
+                            }
+                        }
                     } else {
                         // Handle other tools with left/right mouse buttons
                         if (response.clicked_by(egui::PointerButton::Primary) || 
@@ -1595,6 +4364,16 @@ impl eframe::App for MyApp {
                         }
                     }
 
+                    // Selection outline, black and white so it shows on any color
+                    if let Some(selection) = paint_app.selection {
+                        let selection_rect = to_canvas.inverse().transform_rect(Rect::from_min_size(
+                            Pos2::new(selection.x as f32, selection.y as f32),
+                            Vec2::new(selection.width as f32, selection.height as f32),
+                        ));
+                        painter.rect_stroke(selection_rect, 0.0, Stroke::new(3.0, Color32::BLACK));
+                        painter.rect_stroke(selection_rect, 0.0, Stroke::new(1.0, Color32::WHITE));
+                    }
+
                     // Improved zooming with mouse wheel
                     let delta = ui.input(|i| i.scroll_delta.y);
                     if delta != 0.0 {
@@ -1627,4 +4406,145 @@ fn main() -> eframe::Result<()> {
         native_options,
         Box::new(|_cc| Box::new(MyApp::default())),
     )
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Paint part of a row as one step
+    fn stroke(app: &mut PaintApp, y: usize, color: Color32) {
+        app.paint_pixels((0..20).map(|x| (x, y)).collect(), Some(color));
+        app.save_state();
+    }
+
+    #[test]
+    fn sixteen_bit_samples_survive_rustiq() {
+        // Samples spread over the whole range, some pixels at the lowest alpha
+        let samples: Vec<u16> = (0..70 * 50 * 4)
+            .map(|i| if i % 4 == 3 && i % 3 == 0 { 1 } else { (i * 4099 % 65536) as u16 })
+            .map(|v| v.max(1))
+            .collect();
+        let dir = std::env::temp_dir();
+        let name = |suffix: &str| dir.join(format!("rustique-16-{}{}", std::process::id(), suffix)).to_str().unwrap().to_string();
+        let (input, document, output) = (name(".png"), name(".rustiq"), name("-out.png"));
+        image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(70, 50, samples.clone()).unwrap().save(&input).unwrap();
+
+        let result = (|| {
+            let mut app = PaintApp::open_file(&input, Language::English, true)?;
+            app.save_as_rustiq(&document)?;
+            let mut reopened = PaintApp::open_file(&document, Language::English, true)?;
+            assert_eq!(reopened.current_state.bit_depth, BitDepth::Sixteen);
+            assert!(reopened.current_state.layers[0].data == app.current_state.layers[0].data);
+            reopened.save_as_image(&output, ImageFormat::Png)?;
+            image::open(&output).map_err(|e| e.to_string())
+        })();
+        for path in [&input, &document, &output] {
+            fs::remove_file(path).ok();
+        }
+        assert_eq!(result.unwrap().into_rgba16().into_raw(), samples);
+    }
+
+    #[test]
+    fn broken_depths_are_fixed_on_load() {
+        let mut app = PaintApp::new(100, 80, BitDepth::Eight, Language::English);
+        let layers = &mut app.current_state.layers;
+        layers.push(Layer::new_group("Group".to_string(), 1));
+        layers.push(Layer::new_raster("Top".to_string(), PixelGrid::new(100, 80, BitDepth::Eight), 2));
+        // The last entry is nested and the first one skips a level
+        layers[0].depth = 3;
+
+        let path = std::env::temp_dir().join(format!("rustique-depths-{}.rustiq", std::process::id()));
+        let path = path.to_str().unwrap();
+        app.save_as_rustiq(path).unwrap();
+        let reopened = PaintApp::open_file(path, Language::English, true);
+        fs::remove_file(path).ok();
+        let mut reopened = reopened.unwrap();
+
+        let depths: Vec<usize> = reopened.current_state.layers.iter().map(|layer| layer.depth).collect();
+        assert_eq!(depths, vec![1, 0, 0]);
+        reopened.merge_visible("Merged".to_string());
+        assert_eq!(reopened.current_state.layers.len(), 1);
+    }
+
+    // Size of the history measured from scratch
+    fn measured_history_size(app: &mut PaintApp) -> usize {
+        app.history_shared = None;
+        let live = app.live_tiles();
+        app.refresh_history_sizes(&live);
+        app.history_size()
+    }
+
+    #[test]
+    fn snapshots_count_the_tiles_the_document_let_go() {
+        let mut app = PaintApp::new(300, 200, BitDepth::Eight, Language::English);
+        app.paint_pixels((0..200).flat_map(|y| (0..300).map(move |x| (x, y))).collect(), Some(Color32::RED));
+        app.save_state();
+        app.add_layer("Layer".to_string());
+        let shared_size = app.history_size();
+
+        // Painting the background copies the tiles the snapshot shares
+        app.current_state.active_layer_index = 0;
+        stroke(&mut app, 5, Color32::BLUE);
+        let tile_size = TILE_AREA * 4;
+        assert!(app.history_size() >= shared_size + tile_size);
+        let size = app.history_size();
+        assert_eq!(measured_history_size(&mut app), size);
+
+        // Undoing past the snapshot swaps it back in, then a new branch is painted
+        stroke(&mut app, 6, Color32::GREEN);
+        app.undo();
+        app.undo();
+        app.undo();
+        stroke(&mut app, 100, Color32::BLUE);
+        let size = app.history_size();
+        assert_eq!(measured_history_size(&mut app), size);
+    }
+
+    #[test]
+    fn rustiq_keeps_the_history() {
+        let mut app = PaintApp::new(100, 80, BitDepth::Eight, Language::English);
+        stroke(&mut app, 5, Color32::RED);
+        stroke(&mut app, 6, Color32::BLUE);
+        app.undo();
+        stroke(&mut app, 7, Color32::GREEN);
+        app.add_layer("Layer".to_string());
+
+        let path = std::env::temp_dir().join(format!("rustique-history-{}.rustiq", std::process::id()));
+        let path = path.to_str().unwrap();
+        app.save_as_rustiq(path).unwrap();
+        let reopened = PaintApp::open_file(path, Language::English, true);
+        fs::remove_file(path).ok();
+        let mut reopened = reopened.unwrap();
+
+        assert_eq!(reopened.undo_tree.len(), app.undo_tree.len());
+        assert_eq!(reopened.undo_tree.current(), app.undo_tree.current());
+        assert_eq!(reopened.undo_tree.rows(), app.undo_tree.rows());
+        assert_eq!(reopened.history_size(), app.history_size());
+
+        // The undone branch can still be replayed
+        reopened.jump_to_history(Some(1));
+        assert_eq!(reopened.current_state.layers.len(), 1);
+        assert_eq!(reopened.current_state.get(0, 6), Some(Color32::BLUE));
+        assert_eq!(reopened.current_state.get(0, 7), None);
+        reopened.jump_to_history(None);
+        assert_eq!(reopened.current_state.get(0, 5), None);
+    }
+
+    #[test]
+    fn rustiq_without_history() {
+        let mut app = PaintApp::new(100, 80, BitDepth::Eight, Language::English);
+        stroke(&mut app, 5, Color32::RED);
+        app.save_history = false;
+
+        let path = std::env::temp_dir().join(format!("rustique-no-history-{}.rustiq", std::process::id()));
+        let path = path.to_str().unwrap();
+        app.save_as_rustiq(path).unwrap();
+        let reopened = PaintApp::open_file(path, Language::English, true);
+        fs::remove_file(path).ok();
+        let reopened = reopened.unwrap();
+
+        assert!(reopened.undo_tree.is_empty());
+        assert_eq!(reopened.current_state.get(0, 5), Some(Color32::RED));
+    }
+}
@@ -1,11 +1,13 @@
 use eframe::egui;
 use egui::{Color32, Vec2, Stroke, RichText};
 use crate::localization::{Language, get_text};
+use crate::pixels::BitDepth;
 
 // Enum to represent actions from the main menu
 pub enum MenuAction {
-    NewCanvas(u32, u32),
-    OpenFile,
+    NewCanvas(u32, u32, BitDepth),
+    // Whether tagged images are converted to sRGB
+    OpenFile(bool),
 }
 
 // Result from main menu
@@ -17,6 +19,8 @@ pub enum MenuResult {
 pub struct MainMenu {
     width: u32,
     height: u32,
+    bit_depth: BitDepth,
+    convert_profiles: bool,
     logo: Option<egui::TextureHandle>,
     logo_size: f32,
     language: Language,
@@ -27,6 +31,8 @@ impl MainMenu {
         Self {
             width: 800,
             height: 600,
+            bit_depth: BitDepth::Eight,
+            convert_profiles: true,
             logo: None,
             logo_size: 150.0, // This is now the target height of the logo
             language,
@@ -121,6 +127,13 @@ impl MainMenu {
                                 ui.add(egui::DragValue::new(&mut self.height).speed(1).clamp_range(100..=4000));
                             });
 
+                            ui.horizontal(|ui| {
+                                ui.label(RichText::new(get_text("bit_depth", self.language)).size(16.0));
+                                for depth in BitDepth::ALL {
+                                    ui.selectable_value(&mut self.bit_depth, depth, RichText::new(get_text(depth.text_key(), self.language)).size(16.0));
+                                }
+                            });
+
                             ui.add_space(25.0);
 
                             // Big button with nice styling
@@ -129,7 +142,7 @@ impl MainMenu {
                                 .color(Color32::WHITE);
                             
                             if ui.add(egui::Button::new(button_text).min_size(Vec2::new(200.0, 36.0))).clicked() {
-                                result = Some(MenuResult::Action(MenuAction::NewCanvas(self.width, self.height)));
+                                result = Some(MenuResult::Action(MenuAction::NewCanvas(self.width, self.height, self.bit_depth)));
                             }
 
                             ui.add_space(15.0);
@@ -138,8 +151,10 @@ impl MainMenu {
                                 .size(16.0);
                             
                             if ui.add(egui::Button::new(open_file_text).min_size(Vec2::new(180.0, 30.0))).clicked() {
-                                result = Some(MenuResult::Action(MenuAction::OpenFile));
+                                result = Some(MenuResult::Action(MenuAction::OpenFile(self.convert_profiles)));
                             }
+                            
+                            ui.checkbox(&mut self.convert_profiles, RichText::new(get_text("convert_profile_on_open", self.language)).size(14.0));
                         });
                     });
                 
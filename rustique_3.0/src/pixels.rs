@@ -0,0 +1,444 @@
+use std::ops::Range;
+
+use serde::{Serialize, Deserialize};
+
+use crate::compositing::{self, Rgba};
+use crate::tiles::{TileGrid, TileCoord, TILE_AREA};
+
+// Precision of the pixels of a document, chosen when it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    Sixteen,
+    Float,
+}
+
+impl BitDepth {
+    pub const ALL: [BitDepth; 3] = [BitDepth::Eight, BitDepth::Sixteen, BitDepth::Float];
+
+    // Localization key of the depth name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            BitDepth::Eight => "depth_8",
+            BitDepth::Sixteen => "depth_16",
+            BitDepth::Float => "depth_float",
+        }
+    }
+
+    // A color as it reads back once stored at this depth
+    pub fn quantize(&self, color: Option<Rgba>) -> Option<Rgba> {
+        match self {
+            BitDepth::Eight => store::<[u8; 4]>(color).map(Channels::to_rgba),
+            BitDepth::Sixteen => store::<[u16; 4]>(color).map(Channels::to_rgba),
+            BitDepth::Float => store::<[f32; 4]>(color).map(Channels::to_rgba),
+        }
+    }
+}
+
+// Channel storage for one bit depth. Values are straight (unassociated) RGBA so a pixel
+// keeps its exact color whatever its alpha, premultiplying only happens on the way out.
+pub trait Channels: Copy + PartialEq + Send + Sync {
+    // Size of a pixel in raw tile copies
+    const BYTES: usize;
+
+    fn to_straight(self) -> [f32; 4];
+    fn from_straight(color: [f32; 4]) -> Self;
+    fn write_bytes(self, out: &mut Vec<u8>);
+    fn read_bytes(bytes: &[u8]) -> Self;
+
+    // Premultiplied color used by compositing and the tools
+    #[inline]
+    fn to_rgba(self) -> Rgba {
+        compositing::premultiply(self.to_straight())
+    }
+
+    #[inline]
+    fn from_rgba(color: Rgba) -> Self {
+        Self::from_straight(compositing::unpremultiply(color))
+    }
+}
+
+impl Channels for [u8; 4] {
+    const BYTES: usize = 4;
+
+    #[inline]
+    fn to_straight(self) -> [f32; 4] {
+        self.map(|v| v as f32 / 255.0)
+    }
+
+    #[inline]
+    fn from_straight(color: [f32; 4]) -> Self {
+        color.map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+    }
+
+    fn write_bytes(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self);
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        [bytes[0], bytes[1], bytes[2], bytes[3]]
+    }
+}
+
+impl Channels for [u16; 4] {
+    const BYTES: usize = 8;
+
+    #[inline]
+    fn to_straight(self) -> [f32; 4] {
+        self.map(|v| v as f32 / 65535.0)
+    }
+
+    #[inline]
+    fn from_straight(color: [f32; 4]) -> Self {
+        color.map(|v| (v.clamp(0.0, 1.0) * 65535.0).round() as u16)
+    }
+
+    fn write_bytes(self, out: &mut Vec<u8>) {
+        for v in self {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        std::array::from_fn(|i| u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]))
+    }
+}
+
+impl Channels for [f32; 4] {
+    const BYTES: usize = 16;
+
+    #[inline]
+    fn to_straight(self) -> [f32; 4] {
+        self
+    }
+
+    #[inline]
+    fn from_straight(color: [f32; 4]) -> Self {
+        [color[0], color[1], color[2], color[3].clamp(0.0, 1.0)]
+    }
+
+    fn write_bytes(self, out: &mut Vec<u8>) {
+        for v in self {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        std::array::from_fn(|i| f32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]))
+    }
+}
+
+// Store a premultiplied color at a precision, fully transparent pixels are None
+#[inline]
+pub fn store<C: Channels>(color: Option<Rgba>) -> Option<C> {
+    let stored = C::from_rgba(color?);
+    (stored.to_straight()[3] > 0.0).then_some(stored)
+}
+
+// Same for a straight color, as read from or written to files
+#[inline]
+fn store_straight<C: Channels>(color: [f32; 4]) -> Option<C> {
+    let stored = C::from_straight(color);
+    (stored.to_straight()[3] > 0.0).then_some(stored)
+}
+
+// Pixels of a raster layer at the document bit depth. Editing and compositing work with
+// premultiplied `Rgba` values rounded to the precision when stored, file input and
+// output use the straight values so they are copied without any loss.
+#[derive(Clone, PartialEq)]
+pub enum PixelGrid {
+    Eight(TileGrid<Option<[u8; 4]>>),
+    Sixteen(TileGrid<Option<[u16; 4]>>),
+    Float(TileGrid<Option<[f32; 4]>>),
+}
+
+// Run the same code on the grid of any bit depth
+macro_rules! with_grid {
+    ($pixels:expr, $grid:ident => $body:expr) => {
+        match $pixels {
+            PixelGrid::Eight($grid) => $body,
+            PixelGrid::Sixteen($grid) => $body,
+            PixelGrid::Float($grid) => $body,
+        }
+    };
+}
+
+impl PixelGrid {
+    pub fn new(width: usize, height: usize, depth: BitDepth) -> Self {
+        match depth {
+            BitDepth::Eight => PixelGrid::Eight(TileGrid::new(width, height, None)),
+            BitDepth::Sixteen => PixelGrid::Sixteen(TileGrid::new(width, height, None)),
+            BitDepth::Float => PixelGrid::Float(TileGrid::new(width, height, None)),
+        }
+    }
+
+    // Build a grid from row-major colors
+    pub fn from_rgba(width: usize, height: usize, depth: BitDepth, pixels: &[Option<Rgba>]) -> Self {
+        fn build<C: Channels>(width: usize, height: usize, pixels: &[Option<Rgba>]) -> TileGrid<Option<C>> {
+            let stored: Vec<Option<C>> = pixels.iter().map(|&color| store(color)).collect();
+            TileGrid::from_pixels(width, height, None, &stored)
+        }
+        match depth {
+            BitDepth::Eight => PixelGrid::Eight(build(width, height, pixels)),
+            BitDepth::Sixteen => PixelGrid::Sixteen(build(width, height, pixels)),
+            BitDepth::Float => PixelGrid::Float(build(width, height, pixels)),
+        }
+    }
+
+    // Build a grid from row-major straight colors, fully transparent ones are left empty
+    pub fn from_straight(width: usize, height: usize, depth: BitDepth, pixels: &[[f32; 4]]) -> Self {
+        fn build<C: Channels>(width: usize, height: usize, pixels: &[[f32; 4]]) -> TileGrid<Option<C>> {
+            let stored: Vec<Option<C>> = pixels.iter().map(|&color| store_straight(color)).collect();
+            TileGrid::from_pixels(width, height, None, &stored)
+        }
+        match depth {
+            BitDepth::Eight => PixelGrid::Eight(build(width, height, pixels)),
+            BitDepth::Sixteen => PixelGrid::Sixteen(build(width, height, pixels)),
+            BitDepth::Float => PixelGrid::Float(build(width, height, pixels)),
+        }
+    }
+
+    // Row-major straight colors, empty pixels are transparent black
+    pub fn to_straight(&self) -> Vec<[f32; 4]> {
+        with_grid!(self, grid => grid.to_pixels().into_iter().map(|pixel| pixel.map_or([0.0; 4], Channels::to_straight)).collect())
+    }
+
+    pub fn depth(&self) -> BitDepth {
+        match self {
+            PixelGrid::Eight(_) => BitDepth::Eight,
+            PixelGrid::Sixteen(_) => BitDepth::Sixteen,
+            PixelGrid::Float(_) => BitDepth::Float,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        with_grid!(self, grid => grid.width())
+    }
+
+    pub fn height(&self) -> usize {
+        with_grid!(self, grid => grid.height())
+    }
+
+    #[inline]
+    pub fn get(&self, x: usize, y: usize) -> Option<Rgba> {
+        with_grid!(self, grid => grid.get(x, y).map(Channels::to_rgba))
+    }
+
+    #[inline]
+    pub fn set(&mut self, x: usize, y: usize, color: Option<Rgba>) {
+        with_grid!(self, grid => grid.set(x, y, store(color)))
+    }
+
+    // Every tile position covering the grid, written or not
+    pub fn tile_coords(&self) -> Vec<TileCoord> {
+        with_grid!(self, grid => grid.tile_coords())
+    }
+
+    #[inline]
+    pub fn has_tile(&self, coord: TileCoord) -> bool {
+        with_grid!(self, grid => grid.has_tile(coord))
+    }
+
+    pub fn stored_tiles(&self) -> Vec<TileCoord> {
+        with_grid!(self, grid => grid.stored_tiles().collect())
+    }
+
+    // Colors of a written tile, None when the tile is empty
+    pub fn tile(&self, coord: TileCoord) -> Option<Vec<Option<Rgba>>> {
+        self.tile_pixels(coord, 0..TILE_AREA)
+    }
+
+    // Colors of some pixels of a written tile, given as indices in the tile
+    pub fn tile_pixels(&self, coord: TileCoord, pixels: Range<usize>) -> Option<Vec<Option<Rgba>>> {
+        with_grid!(self, grid => grid.tile(coord).map(|tile| {
+            tile[pixels].iter().map(|pixel| pixel.map(Channels::to_rgba)).collect()
+        }))
+    }
+
+    pub fn insert_tile(&mut self, coord: TileCoord, colors: Vec<Option<Rgba>>) {
+        with_grid!(self, grid => grid.insert_tile(coord, colors.into_iter().map(store).collect()))
+    }
+
+    pub fn compact_tile(&mut self, coord: TileCoord) {
+        with_grid!(self, grid => grid.compact_tile(coord))
+    }
+
+    // Exact copy of a written tile as little endian channels, empty pixels are all zeros
+    // since stored pixels always have some alpha
+    pub fn tile_bytes(&self, coord: TileCoord) -> Option<Vec<u8>> {
+        fn pack<C: Channels>(tile: &[Option<C>]) -> Vec<u8> {
+            let mut bytes = Vec::with_capacity(tile.len() * C::BYTES);
+            for pixel in tile {
+                match pixel {
+                    Some(pixel) => pixel.write_bytes(&mut bytes),
+                    None => bytes.resize(bytes.len() + C::BYTES, 0),
+                }
+            }
+            bytes
+        }
+        with_grid!(self, grid => grid.tile(coord).map(|tile| pack(tile)))
+    }
+
+    // Put back a tile copied by `tile_bytes`, None empties it
+    pub fn set_tile_bytes(&mut self, coord: TileCoord, bytes: Option<&[u8]>) {
+        fn unpack<C: Channels>(bytes: &[u8]) -> Vec<Option<C>> {
+            bytes.chunks_exact(C::BYTES)
+                .map(|pixel| Some(C::read_bytes(pixel)).filter(|pixel| pixel.to_straight()[3] > 0.0))
+                .collect()
+        }
+        with_grid!(self, grid => match bytes {
+            Some(bytes) => grid.insert_tile(coord, unpack(bytes)),
+            None => grid.remove_tile(coord),
+        })
+    }
+
+    // See `TileGrid::tile_addresses`
+    pub fn tile_addresses(&self) -> Vec<(TileCoord, usize, usize)> {
+        with_grid!(self, grid => grid.tile_addresses().collect())
+    }
+
+    // See `TileGrid::share_tile`, the tile is converted to this depth when the depths differ
+    pub fn share_tile(&mut self, coord: TileCoord, from: &PixelGrid, from_coord: TileCoord) {
+        match (&mut *self, from) {
+            (PixelGrid::Eight(grid), PixelGrid::Eight(from)) => grid.share_tile(coord, from, from_coord),
+            (PixelGrid::Sixteen(grid), PixelGrid::Sixteen(from)) => grid.share_tile(coord, from, from_coord),
+            (PixelGrid::Float(grid), PixelGrid::Float(from)) => grid.share_tile(coord, from, from_coord),
+            _ => {
+                if let Some(colors) = from.tile(from_coord) {
+                    self.insert_tile(coord, colors);
+                }
+            },
+        }
+    }
+
+    pub fn bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        with_grid!(self, grid => grid.bounds())
+    }
+
+    // Row-major copy of the whole grid
+    pub fn to_rgba(&self) -> Vec<Option<Rgba>> {
+        with_grid!(self, grid => grid.to_pixels().into_iter().map(|pixel| pixel.map(Channels::to_rgba)).collect())
+    }
+
+    // See `TileGrid::remap`, pixels are moved without any loss
+    pub fn remap<F: Fn(usize, usize) -> Option<(usize, usize)>>(&self, width: usize, height: usize, to: F) -> Self {
+        match self {
+            PixelGrid::Eight(grid) => PixelGrid::Eight(grid.remap(width, height, to)),
+            PixelGrid::Sixteen(grid) => PixelGrid::Sixteen(grid.remap(width, height, to)),
+            PixelGrid::Float(grid) => PixelGrid::Float(grid.remap(width, height, to)),
+        }
+    }
+
+    // Bake a mask into the alpha, only tiles holding both pixels and a partly hidden mask change
+    pub fn apply_mask(&mut self, mask: &TileGrid<u8>) {
+        for coord in self.stored_tiles() {
+            let (Some(colors), Some(values)) = (self.tile(coord), mask.tile(coord)) else { continue };
+            let masked = colors.into_iter()
+                .zip(values.iter())
+                .map(|(color, &value)| color.map(|color| compositing::scale(color, value as f32 / 255.0)))
+                .collect();
+            self.insert_tile(coord, masked);
+        }
+    }
+}
+
+impl Default for PixelGrid {
+    fn default() -> Self {
+        PixelGrid::new(0, 0, BitDepth::Eight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::TILE_SIZE;
+
+    fn bytes_round_trip<C: Channels + std::fmt::Debug>(pixel: C) {
+        let mut bytes = Vec::new();
+        pixel.write_bytes(&mut bytes);
+        assert_eq!(bytes.len(), C::BYTES);
+        assert_eq!(C::read_bytes(&bytes), pixel);
+    }
+
+    #[test]
+    fn channels_read_back_their_bytes() {
+        bytes_round_trip([200u8, 0, 255, 1]);
+        bytes_round_trip([65535u16, 0, 257, 1]);
+        bytes_round_trip([-0.5f32, 1.5, 0.25, 1.0 / 255.0]);
+    }
+
+    // Straight colors with every alpha from fully transparent up, a few of them at alpha 1/255
+    fn test_pixels(depth: BitDepth) -> Vec<[f32; 4]> {
+        let max = match depth {
+            BitDepth::Eight => 255.0,
+            _ => 65535.0,
+        };
+        (0..100 * 70)
+            .map(|i| {
+                let alpha = if i % 3 == 0 { 1.0 / 255.0 } else { (i % 256) as f32 / 255.0 };
+                [(i % 97) as f32 / max, (i * 7 % 251) as f32 / max, 1.0, alpha]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn low_alpha_colors_are_kept() {
+        for depth in BitDepth::ALL {
+            let pixels = test_pixels(depth);
+            let grid = PixelGrid::from_straight(100, 70, depth, &pixels);
+            // The test values fall on the steps of every depth, only transparent pixels change
+            let expected: Vec<[f32; 4]> = pixels.iter()
+                .map(|&color| if color[3] > 0.0 { color } else { [0.0; 4] })
+                .collect();
+            assert_eq!(grid.to_straight(), expected, "{depth:?}");
+        }
+    }
+
+    #[test]
+    fn float_keeps_out_of_range_values() {
+        let grid = PixelGrid::from_straight(1, 1, BitDepth::Float, &[[-0.5, 1.5, 0.25, 1.2]]);
+        assert_eq!(grid.to_straight(), vec![[-0.5, 1.5, 0.25, 1.0]]);
+    }
+
+    #[test]
+    fn tile_bytes_round_trip() {
+        for depth in BitDepth::ALL {
+            let grid = PixelGrid::from_straight(100, 70, depth, &test_pixels(depth));
+            let mut copy = PixelGrid::new(100, 70, depth);
+            for coord in grid.stored_tiles() {
+                let bytes = grid.tile_bytes(coord).unwrap();
+                assert_eq!(bytes.len(), TILE_AREA * match depth {
+                    BitDepth::Eight => 4,
+                    BitDepth::Sixteen => 8,
+                    BitDepth::Float => 16,
+                });
+                copy.set_tile_bytes(coord, Some(&bytes));
+            }
+            assert!(copy == grid, "{depth:?}");
+            copy.set_tile_bytes((0, 0), None);
+            assert!(!copy.has_tile((0, 0)));
+        }
+    }
+
+    #[test]
+    fn shared_tiles_convert_between_depths() {
+        let eight = PixelGrid::from_straight(100, 70, BitDepth::Eight, &test_pixels(BitDepth::Eight));
+        for depth in BitDepth::ALL {
+            let mut grid = PixelGrid::new(200, 70, depth);
+            grid.share_tile((1, 0), &eight, (0, 0));
+            assert!(grid.has_tile((1, 0)), "{depth:?}");
+            for p in [0, 1, 65, TILE_AREA - 1] {
+                let (x, y) = (p % TILE_SIZE, p / TILE_SIZE);
+                let (expected, shared) = (eight.get(x, y), grid.get(x + TILE_SIZE, y));
+                assert!(match (expected, shared) {
+                    (Some(expected), Some(shared)) => expected.iter().zip(shared).all(|(a, b)| (a - b).abs() < 1e-6),
+                    (expected, shared) => expected == shared,
+                }, "{depth:?}: {expected:?} != {shared:?}");
+            }
+            // An empty tile leaves nothing to share
+            grid.share_tile((0, 0), &PixelGrid::new(100, 70, BitDepth::Eight), (0, 0));
+            assert!(!grid.has_tile((0, 0)));
+        }
+    }
+}
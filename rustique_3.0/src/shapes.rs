@@ -0,0 +1,273 @@
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+use serde::{Serialize, Deserialize};
+
+// Shapes drawn by dragging a box on the canvas
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShapeKind {
+    Rectangle,
+    RoundedRectangle,
+    Ellipse,
+    // Regular polygon inscribed in the box
+    Polygon,
+}
+
+impl ShapeKind {
+    pub const ALL: [ShapeKind; 4] = [
+        ShapeKind::Rectangle,
+        ShapeKind::RoundedRectangle,
+        ShapeKind::Ellipse,
+        ShapeKind::Polygon,
+    ];
+
+    // Localization key of the shape name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            ShapeKind::Rectangle => "shape_rectangle",
+            ShapeKind::RoundedRectangle => "shape_rounded_rectangle",
+            ShapeKind::Ellipse => "shape_ellipse",
+            ShapeKind::Polygon => "shape_polygon",
+        }
+    }
+}
+
+// What is painted: the outline with the brush, the inside, or both
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShapeStyle {
+    Outline,
+    Fill,
+    OutlineFill,
+}
+
+impl ShapeStyle {
+    pub const ALL: [ShapeStyle; 3] = [ShapeStyle::Outline, ShapeStyle::Fill, ShapeStyle::OutlineFill];
+
+    // Localization key of the style name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            ShapeStyle::Outline => "shape_outline",
+            ShapeStyle::Fill => "shape_fill",
+            ShapeStyle::OutlineFill => "shape_outline_fill",
+        }
+    }
+
+    pub fn outlined(&self) -> bool {
+        *self != ShapeStyle::Fill
+    }
+
+    pub fn filled(&self) -> bool {
+        *self != ShapeStyle::Outline
+    }
+}
+
+// Box covered by a shape dragged from pixel `start` to pixel `end`, as (left, top, right, bottom)
+// with pixel (x, y) spanning x..x+1. `square` makes the sides equal, `from_center` grows
+// the box around `start`.
+pub fn shape_bounds(start: (i32, i32), end: (i32, i32), square: bool, from_center: bool) -> (f32, f32, f32, f32) {
+    let (mut dx, mut dy) = (end.0 - start.0, end.1 - start.1);
+    if square {
+        let side = dx.abs().max(dy.abs());
+        dx = side * if dx < 0 { -1 } else { 1 };
+        dy = side * if dy < 0 { -1 } else { 1 };
+    }
+    let (left, top, right, bottom) = if from_center {
+        (start.0 - dx.abs(), start.1 - dy.abs(), start.0 + dx.abs(), start.1 + dy.abs())
+    } else {
+        (start.0.min(start.0 + dx), start.1.min(start.1 + dy), start.0.max(start.0 + dx), start.1.max(start.1 + dy))
+    };
+    (left as f32, top as f32, (right + 1) as f32, (bottom + 1) as f32)
+}
+
+// Box through the centers of its border pixels, where outlines are drawn
+pub fn inset_bounds((left, top, right, bottom): (f32, f32, f32, f32)) -> (f32, f32, f32, f32) {
+    (left + 0.5, top + 0.5, right - 0.5, bottom - 0.5)
+}
+
+// Corners of the shape going around the box clockwise. Curves are split in segments of a
+// few pixels, small enough that the brush hides them.
+pub fn shape_points(kind: ShapeKind, bounds: (f32, f32, f32, f32), sides: usize, corner_radius: f32) -> Vec<(f32, f32)> {
+    let (left, top, right, bottom) = bounds;
+    let (center_x, center_y) = ((left + right) / 2.0, (top + bottom) / 2.0);
+    let (radius_x, radius_y) = ((right - left) / 2.0, (bottom - top) / 2.0);
+    match kind {
+        ShapeKind::Rectangle => vec![(left, top), (right, top), (right, bottom), (left, bottom)],
+        ShapeKind::RoundedRectangle => {
+            let radius = corner_radius.clamp(0.0, radius_x.min(radius_y));
+            let segments = arc_segments(radius * FRAC_PI_2);
+            // Arc centers from the top right corner, each arc turns a quarter from its start angle
+            let corners = [
+                (right - radius, top + radius, -FRAC_PI_2),
+                (right - radius, bottom - radius, 0.0),
+                (left + radius, bottom - radius, FRAC_PI_2),
+                (left + radius, top + radius, PI),
+            ];
+            corners.iter()
+                .flat_map(|&(x, y, start)| (0..=segments).map(move |i| {
+                    let angle = start + FRAC_PI_2 * i as f32 / segments as f32;
+                    (x + radius * angle.cos(), y + radius * angle.sin())
+                }))
+                .collect()
+        },
+        ShapeKind::Ellipse => {
+            // A multiple of 4 keeps the outline symmetric
+            let segments = arc_segments(PI * (radius_x + radius_y)).next_multiple_of(4).max(8);
+            (0..segments)
+                .map(|i| {
+                    let angle = TAU * i as f32 / segments as f32;
+                    (center_x + radius_x * angle.cos(), center_y + radius_y * angle.sin())
+                })
+                .collect()
+        },
+        ShapeKind::Polygon => {
+            let sides = sides.max(3);
+            // First corner at the top
+            (0..sides)
+                .map(|i| {
+                    let angle = TAU * i as f32 / sides as f32 - FRAC_PI_2;
+                    (center_x + radius_x * angle.cos(), center_y + radius_y * angle.sin())
+                })
+                .collect()
+        },
+    }
+}
+
+// Points along the cubic bezier curve from `start` to `end`, both included. A curve whose
+// control points sit on its ends is the straight segment between them.
+pub fn bezier_points(start: (f32, f32), control_start: (f32, f32), control_end: (f32, f32), end: (f32, f32)) -> Vec<(f32, f32)> {
+    if control_start == start && control_end == end {
+        return vec![start, end];
+    }
+    let distance = |a: (f32, f32), b: (f32, f32)| (b.0 - a.0).hypot(b.1 - a.1);
+    // The control polygon is never shorter than the curve
+    let length = distance(start, control_start) + distance(control_start, control_end) + distance(control_end, end);
+    let segments = arc_segments(length);
+    (0..=segments)
+        .map(|i| {
+            let t = i as f32 / segments as f32;
+            let u = 1.0 - t;
+            let weights = [u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t];
+            let points = [start, control_start, control_end, end];
+            points.iter().zip(weights).fold((0.0, 0.0), |(x, y), (point, weight)| (x + point.0 * weight, y + point.1 * weight))
+        })
+        .collect()
+}
+
+// Segments for a curve of this length
+fn arc_segments(length: f32) -> usize {
+    ((length / 4.0).ceil() as usize).clamp(1, 1024)
+}
+
+// Pixels of a `width` x `height` canvas whose center lies inside the polygon
+pub fn filled_pixels(points: &[(f32, f32)], width: usize, height: usize) -> Vec<(usize, usize)> {
+    let mut pixels = Vec::new();
+    if points.len() < 3 {
+        return pixels;
+    }
+    let top = points.iter().map(|point| point.1).fold(f32::INFINITY, f32::min).floor().max(0.0) as usize;
+    let bottom = points.iter().map(|point| point.1).fold(f32::NEG_INFINITY, f32::max).ceil().max(0.0) as usize;
+
+    let mut crossings = Vec::new();
+    for y in top..bottom.min(height) {
+        let center_y = y as f32 + 0.5;
+        crossings.clear();
+        for (i, &(x0, y0)) in points.iter().enumerate() {
+            let (x1, y1) = points[(i + 1) % points.len()];
+            if (y0 <= center_y) != (y1 <= center_y) {
+                crossings.push(x0 + (center_y - y0) / (y1 - y0) * (x1 - x0));
+            }
+        }
+        crossings.sort_by(f32::total_cmp);
+        // Even-odd rule, pixels between each pair of crossings are inside
+        for span in crossings.chunks_exact(2) {
+            let start = (span[0] - 0.5).ceil().max(0.0) as usize;
+            let end = ((span[1] - 0.5).ceil().max(0.0) as usize).min(width);
+            pixels.extend((start..end).map(|x| (x, y)));
+        }
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dragged_box_holds_both_end_pixels() {
+        assert_eq!(shape_bounds((2, 3), (5, 7), false, false), (2.0, 3.0, 6.0, 8.0));
+        assert_eq!(shape_bounds((5, 7), (2, 3), false, false), (2.0, 3.0, 6.0, 8.0));
+        assert_eq!(shape_bounds((4, 4), (4, 4), false, false), (4.0, 4.0, 5.0, 5.0));
+    }
+
+    #[test]
+    fn square_follows_the_longer_side() {
+        // Dragged up and left, the box keeps growing away from the start
+        assert_eq!(shape_bounds((10, 10), (7, 4), true, false), (4.0, 4.0, 11.0, 11.0));
+        assert_eq!(shape_bounds((10, 10), (12, 4), true, false), (10.0, 4.0, 17.0, 11.0));
+    }
+
+    #[test]
+    fn from_center_grows_around_the_start() {
+        let (left, top, right, bottom) = shape_bounds((10, 10), (13, 12), false, true);
+        assert_eq!((left, top, right, bottom), (7.0, 8.0, 14.0, 13.0));
+        assert_eq!(((left + right) / 2.0, (top + bottom) / 2.0), (10.5, 10.5));
+        assert_eq!(shape_bounds((10, 10), (7, 12), true, true), (7.0, 7.0, 14.0, 14.0));
+    }
+
+    #[test]
+    fn filled_rectangle_covers_its_pixels() {
+        let bounds = shape_bounds((2, 3), (5, 5), false, false);
+        let points = shape_points(ShapeKind::Rectangle, bounds, 0, 0.0);
+        let pixels = filled_pixels(&points, 20, 20);
+        assert_eq!(pixels.len(), 12);
+        assert!(pixels.iter().all(|&(x, y)| (2..6).contains(&x) && (3..6).contains(&y)));
+        // Clipped to the canvas
+        assert_eq!(filled_pixels(&points, 4, 4).len(), 2);
+    }
+
+    #[test]
+    fn corner_radius_is_clamped() {
+        let bounds = (0.0, 0.0, 10.0, 6.0);
+        let points = shape_points(ShapeKind::RoundedRectangle, bounds, 0, 100.0);
+        // Half the shorter side, the arcs start 3 pixels from the corners
+        let close = |(x, y): (f32, f32), (ex, ey): (f32, f32)| (x - ex).abs() < 1e-4 && (y - ey).abs() < 1e-4;
+        assert!(close(points[0], (7.0, 0.0)));
+        assert!(points.iter().any(|&point| close(point, (10.0, 3.0))));
+        assert!(points.iter().all(|&(x, y)| (-1e-4..=10.0001).contains(&x) && (-1e-4..=6.0001).contains(&y)));
+        // A negative radius gives square corners
+        let square = shape_points(ShapeKind::RoundedRectangle, bounds, 0, -5.0);
+        assert!(close(square[0], (10.0, 0.0)));
+    }
+
+    #[test]
+    fn bezier_keeps_its_ends() {
+        let (start, end) = ((1.5, 2.5), (30.5, 12.5));
+        assert_eq!(bezier_points(start, start, end, end), vec![start, end]);
+        let points = bezier_points(start, (5.0, 40.0), (20.0, -10.0), end);
+        assert!(points.len() > 2);
+        assert_eq!((points[0], points[points.len() - 1]), (start, end));
+    }
+
+    #[test]
+    fn collinear_controls_stay_on_the_segment() {
+        let points = bezier_points((0.0, 4.0), (3.0, 4.0), (7.0, 4.0), (10.0, 4.0));
+        assert!(points.iter().all(|&(x, y)| (-1e-4..=10.0001).contains(&x) && (y - 4.0).abs() < 1e-4));
+        assert!(points.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[test]
+    fn huge_curves_have_a_bounded_point_count() {
+        let points = bezier_points((0.0, 0.0), (1e6, 0.0), (-1e6, 1e6), (0.0, 1e6));
+        assert_eq!(points.len(), 1025);
+        assert_eq!(arc_segments(0.0), 1);
+    }
+
+    #[test]
+    fn ellipse_stays_symmetric() {
+        let points = shape_points(ShapeKind::Ellipse, (0.0, 0.0, 40.0, 20.0), 0, 0.0);
+        assert_eq!(points.len() % 4, 0);
+        let quarter = points.len() / 4;
+        for (a, b) in [(0, 2 * quarter), (quarter, 3 * quarter)] {
+            assert!((points[a].0 + points[b].0 - 40.0).abs() < 1e-3 && (points[a].1 + points[b].1 - 20.0).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Side of the square tiles layers are split into
+pub const TILE_SIZE: usize = 64;
+pub const TILE_AREA: usize = TILE_SIZE * TILE_SIZE;
+
+pub type TileCoord = (usize, usize);
+
+// Sparse storage for a width x height grid of values. Tiles that were never written
+// hold the default value and cost nothing, written tiles are shared between copies
+// of the grid (undo snapshots, duplicated layers) until one of them is modified.
+#[derive(Clone, PartialEq)]
+pub struct TileGrid<T> {
+    width: usize,
+    height: usize,
+    default: T,
+    tiles: HashMap<TileCoord, Arc<Vec<T>>>,
+}
+
+impl<T: Copy + PartialEq> TileGrid<T> {
+    pub fn new(width: usize, height: usize, default: T) -> Self {
+        Self {
+            width,
+            height,
+            default,
+            tiles: HashMap::new(),
+        }
+    }
+
+    // Build a grid from row-major values, tiles holding only the default value stay empty
+    pub fn from_pixels(width: usize, height: usize, default: T, pixels: &[T]) -> Self {
+        let mut grid = Self::new(width, height, default);
+        for coord in grid.tile_coords() {
+            let mut tile = vec![default; TILE_AREA];
+            let (x0, y0) = (coord.0 * TILE_SIZE, coord.1 * TILE_SIZE);
+            for y in y0..(y0 + TILE_SIZE).min(height) {
+                for x in x0..(x0 + TILE_SIZE).min(width) {
+                    if let Some(&value) = pixels.get(y * width + x) {
+                        tile[(y - y0) * TILE_SIZE + (x - x0)] = value;
+                    }
+                }
+            }
+            grid.insert_tile(coord, tile);
+        }
+        grid
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    pub fn default_value(&self) -> T {
+        self.default
+    }
+
+    // Number of tiles along each axis
+    pub fn tiles_across(&self) -> (usize, usize) {
+        (self.width.div_ceil(TILE_SIZE), self.height.div_ceil(TILE_SIZE))
+    }
+
+    // Every tile position covering the grid, written or not
+    pub fn tile_coords(&self) -> Vec<TileCoord> {
+        let (across, down) = self.tiles_across();
+        (0..down).flat_map(|ty| (0..across).map(move |tx| (tx, ty))).collect()
+    }
+
+    // Positions of the tiles holding data
+    pub fn stored_tiles(&self) -> impl Iterator<Item = TileCoord> + '_ {
+        self.tiles.keys().copied()
+    }
+
+    #[inline]
+    pub fn tile(&self, coord: TileCoord) -> Option<&Arc<Vec<T>>> {
+        self.tiles.get(&coord)
+    }
+
+    #[inline]
+    pub fn has_tile(&self, coord: TileCoord) -> bool {
+        self.tiles.contains_key(&coord)
+    }
+
+    // Store a full tile unless it only holds the default value
+    pub fn insert_tile(&mut self, coord: TileCoord, tile: Vec<T>) {
+        if tile.len() == TILE_AREA && tile.iter().any(|&value| value != self.default) {
+            self.tiles.insert(coord, Arc::new(tile));
+        } else {
+            self.tiles.remove(&coord);
+        }
+    }
+
+    pub fn remove_tile(&mut self, coord: TileCoord) {
+        self.tiles.remove(&coord);
+    }
+
+    // Position, address and byte size of every written tile. Copies of a grid share their
+    // tiles until they are modified, equal addresses mean the memory is only counted once.
+    pub fn tile_addresses(&self) -> impl Iterator<Item = (TileCoord, usize, usize)> + '_ {
+        self.tiles.iter().map(|(&coord, tile)| (coord, Arc::as_ptr(tile) as usize, TILE_AREA * std::mem::size_of::<T>()))
+    }
+
+    // Use the tile `from` holds at `from_coord` without copying it
+    pub fn share_tile(&mut self, coord: TileCoord, from: &TileGrid<T>, from_coord: TileCoord) {
+        if let Some(tile) = from.tiles.get(&from_coord) {
+            self.tiles.insert(coord, Arc::clone(tile));
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, x: usize, y: usize) -> T {
+        if x >= self.width || y >= self.height {
+            return self.default;
+        }
+        match self.tiles.get(&(x / TILE_SIZE, y / TILE_SIZE)) {
+            Some(tile) => tile[(y % TILE_SIZE) * TILE_SIZE + x % TILE_SIZE],
+            None => self.default,
+        }
+    }
+
+    #[inline]
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let coord = (x / TILE_SIZE, y / TILE_SIZE);
+        let local = (y % TILE_SIZE) * TILE_SIZE + x % TILE_SIZE;
+        match self.tiles.get_mut(&coord) {
+            // Copy the tile first if another grid still shares it
+            Some(tile) => Arc::make_mut(tile)[local] = value,
+            None if value != self.default => {
+                let mut tile = vec![self.default; TILE_AREA];
+                tile[local] = value;
+                self.tiles.insert(coord, Arc::new(tile));
+            },
+            None => {},
+        }
+    }
+
+    // Row-major copy of the whole grid
+    pub fn to_pixels(&self) -> Vec<T> {
+        let mut pixels = vec![self.default; self.width * self.height];
+        for (&(tx, ty), tile) in &self.tiles {
+            let (x0, y0) = (tx * TILE_SIZE, ty * TILE_SIZE);
+            for y in y0..(y0 + TILE_SIZE).min(self.height) {
+                for x in x0..(x0 + TILE_SIZE).min(self.width) {
+                    pixels[y * self.width + x] = tile[(y - y0) * TILE_SIZE + (x - x0)];
+                }
+            }
+        }
+        pixels
+    }
+
+    // Copy of the grid with a new size, each stored value is moved to the position `to`
+    // gives it and dropped when that is None or outside the new size
+    pub fn remap<F: Fn(usize, usize) -> Option<(usize, usize)>>(&self, width: usize, height: usize, to: F) -> Self {
+        let mut grid = Self::new(width, height, self.default);
+        for (&(tx, ty), tile) in &self.tiles {
+            for (p, &value) in tile.iter().enumerate() {
+                let (x, y) = (tx * TILE_SIZE + p % TILE_SIZE, ty * TILE_SIZE + p / TILE_SIZE);
+                if value == self.default || x >= self.width || y >= self.height {
+                    continue;
+                }
+                if let Some((nx, ny)) = to(x, y) {
+                    grid.set(nx, ny, value);
+                }
+            }
+        }
+        grid
+    }
+
+    // Smallest rectangle (x0, y0, x1, y1), ends excluded, holding every value other than the default
+    pub fn bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (&(tx, ty), tile) in &self.tiles {
+            for (p, &value) in tile.iter().enumerate() {
+                let (x, y) = (tx * TILE_SIZE + p % TILE_SIZE, ty * TILE_SIZE + p / TILE_SIZE);
+                if value == self.default || x >= self.width || y >= self.height {
+                    continue;
+                }
+                bounds = Some(match bounds {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)),
+                    None => (x, y, x + 1, y + 1),
+                });
+            }
+        }
+        bounds
+    }
+
+    // Drop a tile that went back to holding only the default value
+    pub fn compact_tile(&mut self, coord: TileCoord) {
+        let default = self.default;
+        if self.tiles.get(&coord).is_some_and(|tile| tile.iter().all(|&value| value == default)) {
+            self.tiles.remove(&coord);
+        }
+    }
+}
+
+impl<T: Copy + PartialEq + Default> Default for TileGrid<T> {
+    fn default() -> Self {
+        Self::new(0, 0, T::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwritten_tiles_stay_empty() {
+        let mut grid = TileGrid::new(100, 70, 0u8);
+        grid.set(70, 10, 0);
+        assert!(!grid.has_tile((1, 0)));
+        grid.set(70, 10, 5);
+        grid.set(100, 10, 9);
+        assert_eq!(grid.get(70, 10), 5);
+        assert_eq!(grid.get(100, 10), 0);
+        assert_eq!(grid.stored_tiles().collect::<Vec<_>>(), vec![(1, 0)]);
+        assert_eq!(grid.tiles_across(), (2, 2));
+    }
+
+    #[test]
+    fn copies_share_tiles_until_modified() {
+        let mut grid = TileGrid::new(64, 64, 0u8);
+        grid.set(1, 1, 5);
+        let mut copy = grid.clone();
+        assert_eq!(copy.tile_addresses().next(), grid.tile_addresses().next());
+        copy.set(1, 1, 6);
+        assert_eq!(grid.get(1, 1), 5);
+        assert_ne!(copy.tile_addresses().next(), grid.tile_addresses().next());
+    }
+
+    #[test]
+    fn pixels_round_trip() {
+        let pixels: Vec<u8> = (0..90 * 70).map(|i| if i % 7 == 0 { (i % 251) as u8 } else { 0 }).collect();
+        let grid = TileGrid::from_pixels(90, 70, 0, &pixels);
+        assert_eq!(grid.to_pixels(), pixels);
+        // Tiles only holding the default value are not stored
+        let sparse = TileGrid::from_pixels(90, 70, 0, &vec![0u8; 90 * 70]);
+        assert_eq!(sparse.stored_tiles().count(), 0);
+    }
+
+    #[test]
+    fn remap_moves_and_drops_values() {
+        let mut grid = TileGrid::new(100, 100, 0u8);
+        grid.set(70, 10, 5);
+        grid.set(3, 3, 7);
+        grid.set(99, 99, 9);
+        // Move everything 30 pixels up and left into a 50 x 50 grid
+        let moved = grid.remap(50, 50, |x, y| (x >= 30).then(|| (x - 30, y)));
+        assert_eq!((moved.width(), moved.height()), (50, 50));
+        assert_eq!(moved.get(40, 10), 5);
+        assert_eq!(moved.to_pixels().iter().filter(|&&value| value != 0).count(), 1);
+    }
+
+    #[test]
+    fn bounds_of_the_values() {
+        let mut grid = TileGrid::new(200, 100, 0u8);
+        assert_eq!(grid.bounds(), None);
+        grid.set(70, 10, 5);
+        grid.set(3, 80, 7);
+        assert_eq!(grid.bounds(), Some((3, 10, 71, 81)));
+        grid.set(3, 80, 0);
+        assert_eq!(grid.bounds(), Some((70, 10, 71, 11)));
+    }
+
+    #[test]
+    fn compact_drops_default_tiles() {
+        let mut grid = TileGrid::new(128, 64, 0u8);
+        grid.set(1, 1, 5);
+        grid.set(65, 1, 5);
+        grid.set(1, 1, 0);
+        grid.compact_tile((0, 0));
+        grid.compact_tile((1, 0));
+        assert!(!grid.has_tile((0, 0)));
+        assert!(grid.has_tile((1, 0)));
+    }
+}
@@ -0,0 +1,278 @@
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma, Rgba};
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::compositing;
+use crate::pixels::{self, Channels, PixelGrid};
+use crate::tiles::TileGrid;
+
+// Interpolation used when scaling the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleFilter {
+    // Keeps hard pixel edges, for pixel art
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl ResampleFilter {
+    pub const ALL: [ResampleFilter; 4] = [
+        ResampleFilter::Nearest,
+        ResampleFilter::Bilinear,
+        ResampleFilter::Bicubic,
+        ResampleFilter::Lanczos,
+    ];
+
+    // Localization key of the filter name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            ResampleFilter::Nearest => "filter_nearest",
+            ResampleFilter::Bilinear => "filter_bilinear",
+            ResampleFilter::Bicubic => "filter_bicubic",
+            ResampleFilter::Lanczos => "filter_lanczos",
+        }
+    }
+
+    fn filter_type(&self) -> FilterType {
+        match self {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Bilinear => FilterType::Triangle,
+            ResampleFilter::Bicubic => FilterType::CatmullRom,
+            ResampleFilter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+// Scale layer pixels to a new size. The premultiplied values are filtered directly so
+// transparent pixels don't bleed their color into the edges, in floating point so every
+// bit depth keeps its precision.
+pub fn resample_pixels(grid: &PixelGrid, width: usize, height: usize, filter: ResampleFilter) -> PixelGrid {
+    let source: Vec<f32> = grid.to_rgba()
+        .into_iter()
+        .flat_map(|color| color.unwrap_or(compositing::TRANSPARENT))
+        .collect();
+    let image = ImageBuffer::<Rgba<f32>, Vec<f32>>::from_raw(grid.width() as u32, grid.height() as u32, source)
+        .unwrap_or_else(|| ImageBuffer::new(grid.width() as u32, grid.height() as u32));
+
+    let scaled = imageops::resize(&image, width as u32, height as u32, filter.filter_type());
+    let pixels: Vec<Option<compositing::Rgba>> = scaled.pixels()
+        .map(|&Rgba([r, g, b, a])| {
+            // Sharpening filters can overshoot, color can't exceed alpha once premultiplied
+            let a = a.clamp(0.0, 1.0);
+            (a > 0.0).then_some([r.clamp(0.0, a), g.clamp(0.0, a), b.clamp(0.0, a), a])
+        })
+        .collect();
+    PixelGrid::from_rgba(width, height, grid.depth(), &pixels)
+}
+
+// Scale a mask to a new size
+pub fn resample_mask(grid: &TileGrid<u8>, width: usize, height: usize, filter: ResampleFilter) -> TileGrid<u8> {
+    let image = ImageBuffer::<Luma<u8>, Vec<u8>>::from_raw(grid.width() as u32, grid.height() as u32, grid.to_pixels())
+        .unwrap_or_else(|| ImageBuffer::from_pixel(grid.width() as u32, grid.height() as u32, Luma([grid.default_value()])));
+    let scaled = imageops::resize(&image, width as u32, height as u32, filter.filter_type());
+    TileGrid::from_pixels(width, height, grid.default_value(), scaled.as_raw())
+}
+
+impl ResampleFilter {
+    // Reach of the interpolation kernel, in source pixels
+    fn support(&self) -> f32 {
+        match self {
+            ResampleFilter::Nearest => 0.5,
+            ResampleFilter::Bilinear => 1.0,
+            ResampleFilter::Bicubic => 2.0,
+            ResampleFilter::Lanczos => 3.0,
+        }
+    }
+
+    // Weight of a source pixel at distance `t` from the sampled position
+    fn kernel(&self, t: f32) -> f32 {
+        let t = t.abs();
+        match self {
+            ResampleFilter::Nearest => if t <= 0.5 { 1.0 } else { 0.0 },
+            ResampleFilter::Bilinear => (1.0 - t).max(0.0),
+            // Catmull-Rom, the same cubic the image crate uses for scaling
+            ResampleFilter::Bicubic => {
+                if t < 1.0 {
+                    1.5 * t * t * t - 2.5 * t * t + 1.0
+                } else if t < 2.0 {
+                    -0.5 * t * t * t + 2.5 * t * t - 4.0 * t + 2.0
+                } else {
+                    0.0
+                }
+            },
+            ResampleFilter::Lanczos => {
+                let sinc = |v: f32| {
+                    if v.abs() < 1e-6 {
+                        1.0
+                    } else {
+                        let v = v * std::f32::consts::PI;
+                        v.sin() / v
+                    }
+                };
+                if t < 3.0 { sinc(t) * sinc(t / 3.0) } else { 0.0 }
+            },
+        }
+    }
+}
+
+// Flips and quarter turns, exact since they only move pixels around
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Orientation {
+    FlipHorizontal,
+    FlipVertical,
+    // Clockwise
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Orientation {
+    pub const ALL: [Orientation; 5] = [
+        Orientation::FlipHorizontal,
+        Orientation::FlipVertical,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+    ];
+
+    // Localization key of the operation name
+    pub fn text_key(&self) -> &'static str {
+        match self {
+            Orientation::FlipHorizontal => "flip_horizontal",
+            Orientation::FlipVertical => "flip_vertical",
+            Orientation::Rotate90 => "rotate_90",
+            Orientation::Rotate180 => "rotate_180",
+            Orientation::Rotate270 => "rotate_270",
+        }
+    }
+
+    // Size of a width x height image once transformed
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Orientation::Rotate90 | Orientation::Rotate270 => (height, width),
+            _ => (width, height),
+        }
+    }
+
+    // Where the pixel (x, y) of a width x height image ends up
+    pub fn map(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Orientation::FlipHorizontal => (width - 1 - x, y),
+            Orientation::FlipVertical => (x, height - 1 - y),
+            Orientation::Rotate90 => (height - 1 - y, x),
+            Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Orientation::Rotate270 => (y, width - 1 - x),
+        }
+    }
+}
+
+// Size of the canvas holding a width x height image rotated by `angle` degrees
+pub fn rotated_size(width: usize, height: usize, angle: f32) -> (usize, usize) {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (w, h) = (width as f32, height as f32);
+    // The small margin keeps exact angles from growing the canvas by a pixel
+    let new_width = (w * cos.abs() + h * sin.abs() - 1e-3).ceil().max(1.0);
+    let new_height = (w * sin.abs() + h * cos.abs() - 1e-3).ceil().max(1.0);
+    (new_width as usize, new_height as usize)
+}
+
+// Rotate a grid clockwise by `angle` degrees around its center and center the result on a
+// `width` x `height` canvas. Values are interpolated with `filter`, `channels` and `pixel`
+// convert to and from premultiplied channels.
+fn rotate_grid<T: Copy + PartialEq + Send + Sync>(
+    grid: &TileGrid<T>,
+    width: usize,
+    height: usize,
+    angle: f32,
+    filter: ResampleFilter,
+    channels: impl Fn(T) -> [f32; 4] + Sync,
+    pixel: impl Fn([f32; 4]) -> T + Sync,
+) -> TileGrid<T> {
+    let source = grid.to_pixels();
+    let (source_width, source_height) = (grid.width(), grid.height());
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (source_cx, source_cy) = (source_width as f32 / 2.0, source_height as f32 / 2.0);
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let outside = channels(grid.default_value());
+    let support = filter.support();
+
+    let pixels: Vec<T> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            // Inverse rotation of the destination pixel center gives the position to sample
+            let (dx, dy) = ((i % width) as f32 + 0.5 - cx, (i / width) as f32 + 0.5 - cy);
+            let sx = dx * cos + dy * sin + source_cx - 0.5;
+            let sy = -dx * sin + dy * cos + source_cy - 0.5;
+
+            let mut sum = [0.0; 4];
+            let mut total = 0.0;
+            for iy in (sy - support).ceil() as i64..=(sy + support).floor() as i64 {
+                let wy = filter.kernel(sy - iy as f32);
+                if wy == 0.0 {
+                    continue;
+                }
+                for ix in (sx - support).ceil() as i64..=(sx + support).floor() as i64 {
+                    let weight = wy * filter.kernel(sx - ix as f32);
+                    if weight == 0.0 {
+                        continue;
+                    }
+                    let value = if ix >= 0 && iy >= 0 && (ix as usize) < source_width && (iy as usize) < source_height {
+                        channels(source[iy as usize * source_width + ix as usize])
+                    } else {
+                        outside
+                    };
+                    for c in 0..4 {
+                        sum[c] += value[c] * weight;
+                    }
+                    total += weight;
+                }
+            }
+            if total > 0.0 {
+                pixel(sum.map(|v| v / total))
+            } else {
+                pixel(outside)
+            }
+        })
+        .collect();
+    TileGrid::from_pixels(width, height, grid.default_value(), &pixels)
+}
+
+// Rotate layer pixels by an arbitrary angle, see `rotate_grid`
+pub fn rotate_pixels(grid: &PixelGrid, width: usize, height: usize, angle: f32, filter: ResampleFilter) -> PixelGrid {
+    fn rotate<C: Channels>(grid: &TileGrid<Option<C>>, width: usize, height: usize, angle: f32, filter: ResampleFilter) -> TileGrid<Option<C>> {
+        rotate_grid(
+            grid,
+            width,
+            height,
+            angle,
+            filter,
+            |color| color.map_or(compositing::TRANSPARENT, Channels::to_rgba),
+            |color| {
+                // Sharpening filters can overshoot, color can't exceed alpha once premultiplied
+                let alpha = color[3].clamp(0.0, 1.0);
+                pixels::store(Some([color[0].clamp(0.0, alpha), color[1].clamp(0.0, alpha), color[2].clamp(0.0, alpha), alpha]))
+            },
+        )
+    }
+
+    match grid {
+        PixelGrid::Eight(grid) => PixelGrid::Eight(rotate(grid, width, height, angle, filter)),
+        PixelGrid::Sixteen(grid) => PixelGrid::Sixteen(rotate(grid, width, height, angle, filter)),
+        PixelGrid::Float(grid) => PixelGrid::Float(rotate(grid, width, height, angle, filter)),
+    }
+}
+
+// Rotate a mask by an arbitrary angle, see `rotate_grid`
+pub fn rotate_mask(grid: &TileGrid<u8>, width: usize, height: usize, angle: f32, filter: ResampleFilter) -> TileGrid<u8> {
+    rotate_grid(
+        grid,
+        width,
+        height,
+        angle,
+        filter,
+        |value| [value as f32 / 255.0, 0.0, 0.0, 0.0],
+        |value| (value[0].clamp(0.0, 1.0) * 255.0).round() as u8,
+    )
+}